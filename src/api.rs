@@ -0,0 +1,518 @@
+//! Request and response bodies of the HTTP API.
+
+use crate::error::ErrorResponse;
+use serde::{Deserialize, Deserializer, Serialize};
+
+#[derive(Debug, Deserialize)]
+pub struct ImageGenerationRequest {
+  pub prompt: String,
+  pub model: String,
+  #[serde(default = "default_size")]
+  pub size: String,
+  /// Name of a server-side style wrapping the prompt and extending the
+  /// negative prompt, as listed by `/v1/styles`.
+  #[serde(default)]
+  pub style: Option<String>,
+  /// Replaces the server's default negative prompt; an empty string
+  /// disables it.
+  #[serde(default)]
+  pub negative_prompt: Option<String>,
+  /// Appends `negative_prompt` to the server's default instead.
+  #[serde(default)]
+  pub append_negative: bool,
+  #[serde(default = "default_steps")]
+  pub steps: u32,
+  #[serde(default = "default_cfg_scale")]
+  pub cfg_scale: f32,
+  /// Random when omitted or -1.
+  #[serde(default, deserialize_with = "deserialize_seed")]
+  pub seed: Option<i64>,
+  #[serde(default = "default_n")]
+  pub n: u32,
+  #[serde(default)]
+  pub response_format: ResponseFormat,
+  #[serde(default)]
+  pub sampler: Option<String>,
+  #[serde(default)]
+  pub loras: Vec<LoraSpec>,
+  /// File name in `SD_CPP_SERVER_VAES` without its extension.
+  #[serde(default)]
+  pub vae: Option<String>,
+  /// Number of final CLIP layers to skip. When omitted the binary picks its
+  /// own default for the model.
+  #[serde(default)]
+  pub clip_skip: Option<i32>,
+  /// Makes repeated runs give byte-identical images on the same host:
+  /// requires a `seed`, runs on one thread (`--threads 1`) and pins the
+  /// sampler to `euler_a` when none is set. GPU backends may still vary.
+  #[serde(default)]
+  pub deterministic: bool,
+  /// Flags passed to the binary, each followed by its values, such as
+  /// `["--control-strength", "0.8"]`. Every flag must be in
+  /// `SD_CPP_SERVER_ALLOWED_EXTRA_FLAGS`.
+  #[serde(default)]
+  pub extra_args: Vec<String>,
+  /// Decodes the image in tiles (`--vae-tiling`), so large sizes fit in
+  /// limited VRAM at the cost of speed.
+  #[serde(default)]
+  pub vae_tiling: bool,
+  /// Uses flash attention in the diffusion model (`--diffusion-fa`), which
+  /// lowers memory use. Binaries built before flash attention support was
+  /// added in 2024 reject the flag.
+  #[serde(default)]
+  pub diffusion_fa: bool,
+  /// Runs the VAE on the CPU (`--vae-on-cpu`), freeing VRAM for the
+  /// diffusion model.
+  #[serde(default)]
+  pub vae_on_cpu: bool,
+  /// Makes the image tile seamlessly by padding circularly
+  /// (`--circular`). Needs a binary whose `--help` lists the flag, and a
+  /// size in multiples of 64.
+  #[serde(default)]
+  pub tiling: bool,
+  #[serde(default)]
+  pub output_format: OutputFormat,
+  #[serde(default)]
+  pub quality: Option<Quality>,
+  /// Returns each image once per entry, such as a small JPEG preview along
+  /// with the full PNG, from a single generation. Replaces `output_format`
+  /// and `quality`.
+  #[serde(default)]
+  pub formats: Vec<FormatSpec>,
+  /// Bits per channel, 8 or 16 (PNG only). The binary renders 8 bits, so
+  /// 16-bit output holds the same colors at a wider depth.
+  #[serde(default)]
+  pub bit_depth: Option<u8>,
+  /// Adds an opaque alpha channel to PNG and WebP output.
+  #[serde(default)]
+  pub alpha: bool,
+  /// `transparent` removes the background with
+  /// `SD_CPP_SERVER_BACKGROUND_REMOVER`, returning PNG or WebP with alpha.
+  #[serde(default)]
+  pub background: Background,
+  /// Model finishing the image, as SDXL's refiner does: the output of
+  /// `model` goes through it as an img2img pass. Not supported by edits,
+  /// inpainting, ControlNet or streaming.
+  #[serde(default)]
+  pub refiner_model: Option<String>,
+  /// Fraction of the denoising done by `model` before the refiner takes
+  /// over, 0.8 by default.
+  #[serde(default)]
+  pub refiner_switch_at: Option<f32>,
+  /// Build of the binary to run, by its name in `SD_CPP_SERVER_BINARIES`.
+  /// Defaults to `SD_CPP_SERVER_DEFAULT_BACKEND`.
+  #[serde(default)]
+  pub backend: Option<String>,
+  /// Index of the GPU to run on, below `SD_CPP_SERVER_GPU_COUNT`.
+  #[serde(default)]
+  pub device: Option<u32>,
+  /// CPU threads used by the binary, defaulting to `SD_CPP_SERVER_THREADS`.
+  #[serde(default)]
+  pub threads: Option<u32>,
+  /// Answers with a job ID at once and posts the outcome to this URL, whose
+  /// host must be in `SD_CPP_SERVER_WEBHOOK_ALLOWED_HOSTS`.
+  #[serde(default)]
+  pub webhook_url: Option<String>,
+  /// Streams intermediate images as `preview` events. Only used by
+  /// `/v1/images/generations/stream`.
+  #[serde(default)]
+  pub preview: bool,
+  /// Writes the generation parameters into a PNG `parameters` text chunk,
+  /// in the format Automatic1111 uses. Requires `output_format: "png"`.
+  #[serde(default)]
+  pub embed_metadata: bool,
+  /// Adds `metadata` to the response, which is otherwise OpenAI-compatible.
+  #[serde(default)]
+  pub include_metadata: bool,
+  /// Adds what the binary printed for each image to `metadata.logs`, with
+  /// tokens and paths redacted. Implies `metadata`.
+  #[serde(default)]
+  pub include_logs: bool,
+  /// Validates the request and answers with the binary's arguments instead
+  /// of running it.
+  #[serde(default)]
+  pub dry_run: bool,
+  /// End user the request is made for, as in OpenAI's API. Logged with the
+  /// generation and rate limited separately from the token's other users.
+  #[serde(default)]
+  pub user: Option<String>,
+}
+
+/// Either an encoder quality or, as in OpenAI's API, a preset of `steps`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(
+  untagged,
+  expecting = "quality must be from 1 to 100, \"standard\" or \"hd\""
+)]
+pub enum Quality {
+  /// Encoder quality from 1 to 100, only accepted for lossy formats.
+  Encoder(u8),
+  Preset(QualityPreset),
+}
+
+/// Overrides the requested `steps`: `standard` runs the default 20 steps
+/// and `hd` `SD_CPP_SERVER_HD_STEPS`, 50 by default.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QualityPreset {
+  Standard,
+  Hd,
+}
+
+impl Quality {
+  pub fn encoder(self) -> Option<u8> {
+    match self {
+      Quality::Encoder(quality) => Some(quality),
+      Quality::Preset(_) => None,
+    }
+  }
+}
+
+/// A variant of every image returned with `formats`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct FormatSpec {
+  pub format: OutputFormat,
+  /// Downscales the image to fit this many pixels on its longer side.
+  /// Images are never upscaled.
+  #[serde(default)]
+  pub max_dimension: Option<u32>,
+  /// Encoder quality from 1 to 100, only accepted for lossy formats.
+  #[serde(default)]
+  pub quality: Option<u8>,
+}
+
+/// A LoRA applied to the generation. `name` is the file name in
+/// `SD_CPP_SERVER_LORAS` without its extension, so `foo` loads
+/// `foo.safetensors` (or `.ckpt` / `.gguf`).
+#[derive(Debug, Deserialize)]
+pub struct LoraSpec {
+  pub name: String,
+  pub weight: f32,
+}
+
+/// Sampling methods accepted by the binary's `--sampling-method`.
+pub const SAMPLERS: &[&str] = &[
+  "euler",
+  "euler_a",
+  "heun",
+  "dpm2",
+  "dpm++2s_a",
+  "dpm++2m",
+  "dpm++2mv2",
+  "ipndm",
+  "ipndm_v",
+  "lcm",
+  "ddim_trailing",
+  "tcd",
+];
+
+#[derive(Deserialize)]
+pub struct ImageEditRequest {
+  #[serde(flatten)]
+  pub generation: ImageGenerationRequest,
+  /// Base64-encoded PNG or JPEG, optionally as a `data:` URL.
+  pub image: String,
+  /// How much the image is changed, from 0.0 (kept as is) to 1.0 (ignored).
+  #[serde(default = "default_strength")]
+  pub strength: f32,
+}
+
+/// `ImageEditRequest` sent as `multipart/form-data`, whose image is a
+/// separate file part.
+#[derive(Deserialize)]
+pub struct ImageEditForm {
+  #[serde(flatten)]
+  pub generation: ImageGenerationRequest,
+  #[serde(default = "default_strength")]
+  pub strength: f32,
+}
+
+/// An edit regenerating only the masked region of `image`.
+#[derive(Deserialize)]
+pub struct ImageInpaintRequest {
+  #[serde(flatten)]
+  pub generation: ImageGenerationRequest,
+  /// Base64-encoded PNG or JPEG, optionally as a `data:` URL.
+  pub image: String,
+  /// Grayscale PNG or JPEG of the same size as `image`, white where it is
+  /// regenerated and black where it is kept.
+  pub mask: String,
+  #[serde(default = "default_strength")]
+  pub strength: f32,
+}
+
+fn default_strength() -> f32 {
+  0.75
+}
+
+#[derive(Deserialize)]
+pub struct ImageControlNetRequest {
+  #[serde(flatten)]
+  pub generation: ImageGenerationRequest,
+  /// Base64-encoded PNG or JPEG guiding the composition, such as a pose,
+  /// depth map or edges, optionally as a `data:` URL.
+  pub control_image: String,
+  /// File name in `SD_CPP_SERVER_CONTROLNETS` without its extension.
+  pub control_net: String,
+}
+
+/// Query of `DELETE /v1/jobs`.
+#[derive(Deserialize)]
+pub struct CancelJobsQuery {
+  /// Only cancels the jobs of this end user.
+  #[serde(default)]
+  pub user: Option<String>,
+}
+
+/// Generates one image per seed from `seed_start` to `seed_end`,
+/// inclusive.
+#[derive(Deserialize)]
+pub struct ImageSweepRequest {
+  #[serde(flatten)]
+  pub generation: ImageGenerationRequest,
+  pub seed_start: i64,
+  pub seed_end: i64,
+}
+
+#[derive(Deserialize)]
+pub struct ImageUpscaleRequest {
+  /// Base64-encoded PNG or JPEG, optionally as a `data:` URL.
+  pub image: String,
+  /// File name in `SD_CPP_SERVER_UPSCALERS` without its extension.
+  pub model: String,
+  #[serde(default = "default_upscale_factor")]
+  pub upscale_factor: u32,
+}
+
+fn default_upscale_factor() -> u32 {
+  4
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFormat {
+  #[default]
+  B64Json,
+  Url,
+  /// A ZIP archive of `image_{index}.{ext}` files, along with a
+  /// `metadata.json` listing their seeds, instead of JSON.
+  Zip,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Background {
+  /// The binary's output as is, which `opaque` also requests explicitly.
+  #[default]
+  Auto,
+  Opaque,
+  Transparent,
+}
+
+/// Encoding of the returned images. The binary always writes PNG, which is
+/// transcoded when another format is requested.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+  #[default]
+  Png,
+  Jpeg,
+  Webp,
+}
+
+impl OutputFormat {
+  pub fn extension(self) -> &'static str {
+    match self {
+      OutputFormat::Png => "png",
+      OutputFormat::Jpeg => "jpeg",
+      OutputFormat::Webp => "webp",
+    }
+  }
+
+  pub fn image_format(self) -> image::ImageFormat {
+    match self {
+      OutputFormat::Png => image::ImageFormat::Png,
+      OutputFormat::Jpeg => image::ImageFormat::Jpeg,
+      OutputFormat::Webp => image::ImageFormat::WebP,
+    }
+  }
+
+  /// Whether `quality` applies. The `image` crate only encodes lossless
+  /// WebP.
+  pub fn is_lossy(self) -> bool {
+    matches!(self, OutputFormat::Jpeg)
+  }
+}
+
+fn default_size() -> String {
+  "512x512".to_string()
+}
+
+/// `refiner_switch_at` of requests with a `refiner_model` but none.
+pub const DEFAULT_REFINER_SWITCH_AT: f32 = 0.8;
+
+/// Steps run when a request sets neither `steps` nor `quality`.
+pub const DEFAULT_STEPS: u32 = 20;
+
+fn default_steps() -> u32 {
+  DEFAULT_STEPS
+}
+
+fn default_cfg_scale() -> f32 {
+  7.0
+}
+
+/// Reads the `-1` seed clients send for a random one as no seed.
+fn deserialize_seed<'de, D: Deserializer<'de>>(
+  deserializer: D,
+) -> Result<Option<i64>, D::Error> {
+  Ok(Option::<i64>::deserialize(deserializer)?.filter(|&seed| seed != -1))
+}
+
+fn default_n() -> u32 {
+  1
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImageGenerationResponse {
+  pub created: u64,
+  pub data: Vec<ImageData>,
+  pub output_format: OutputFormat,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub metadata: Option<GenerationMetadata>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImageSweepResponse {
+  pub created: u64,
+  /// One entry per seed, in order.
+  pub data: Vec<SweepImage>,
+  pub output_format: OutputFormat,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub metadata: Option<GenerationMetadata>,
+}
+
+/// A seed of a sweep, which fails on its own without failing the others.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum SweepImage {
+  Image(ImageData),
+  Failed {
+    seed: i64,
+    #[serde(flatten)]
+    error: ErrorResponse,
+  },
+}
+
+/// Effective generation parameters, so clients can reproduce a result.
+#[derive(Debug, Serialize)]
+pub struct GenerationMetadata {
+  pub duration_ms: u64,
+  /// Seed of the first image; every image reports its own in `data`.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub seed: Option<i64>,
+  pub model: String,
+  /// File of the weights used.
+  pub model_file: String,
+  /// SHA-256 of the weights, when the model was requested by hash or its
+  /// hash was already known.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub model_sha256: Option<String>,
+  pub steps: u32,
+  pub cfg_scale: f32,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub sampler: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub vae: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub clip_skip: Option<i32>,
+  /// Only set for edits.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub strength: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub refiner_model: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub refiner_switch_at: Option<f32>,
+  pub background: Background,
+  /// Unset when running the default `SD_CPP_SERVER_BINARY`.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub backend: Option<String>,
+  /// The prompt likely exceeds the text encoder's window, so its end may
+  /// have been ignored.
+  pub prompt_truncated: bool,
+  /// Likely causes of poor results, such as a size far from the model's
+  /// native resolutions.
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub warnings: Vec<String>,
+  /// Output of the binary for each generated image, with `include_logs`.
+  /// Images answered from the result cache have empty logs.
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub logs: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImageData {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub b64_json: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub url: Option<String>,
+  pub seed: i64,
+  /// The prompt as passed to the binary, with the `style` and LoRA tags
+  /// applied, in OpenAI's field.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub revised_prompt: Option<String>,
+  /// Format of the variant, only set with `formats`.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub format: Option<OutputFormat>,
+  /// Dimensions of the variant as `{width}x{height}`, only set with
+  /// `formats`.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub size: Option<String>,
+  /// Only set with `include_metadata`.
+  #[serde(flatten)]
+  pub info: Option<ImageInfo>,
+}
+
+/// Size of an encoded image, so clients can lay it out before decoding it.
+#[derive(Debug, Serialize)]
+pub struct ImageInfo {
+  pub width: u32,
+  pub height: u32,
+  pub bytes: usize,
+  /// Channels and their depth, such as `rgb8` or `rgba16`.
+  pub color_type: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModelList {
+  pub object: &'static str,
+  pub data: Vec<ModelData>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModelData {
+  pub id: String,
+  pub object: &'static str,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn request(seed: serde_json::Value) -> ImageGenerationRequest {
+    serde_json::from_value(serde_json::json!({
+      "prompt": "a cat",
+      "model": "foo",
+      "seed": seed,
+    }))
+    .unwrap()
+  }
+
+  #[test]
+  fn seed_minus_one_is_random() {
+    assert_eq!(request(serde_json::json!(-1)).seed, None);
+    assert_eq!(request(serde_json::json!(null)).seed, None);
+    assert_eq!(request(serde_json::json!(42)).seed, Some(42));
+    assert_eq!(request(serde_json::json!(-2)).seed, Some(-2));
+  }
+}
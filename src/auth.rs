@@ -0,0 +1,186 @@
+//! Bearer token authentication and per-token or per-user rate limiting.
+
+use crate::config::Context;
+use crate::error::ApiError;
+use actix_web::http::StatusCode;
+use actix_web::HttpRequest;
+use std::time::{Duration, Instant};
+
+pub fn verify_bearer_token(
+  req: &HttpRequest,
+  expected_tokens: &[String],
+) -> Result<(), ApiError> {
+  if let Some(token) = bearer_token(req) {
+    // Check every configured token so timing doesn't reveal which one
+    // matched.
+    let matched = expected_tokens.iter().fold(false, |matched, expected| {
+      constant_time_eq(token.as_bytes(), expected.as_bytes()) | matched
+    });
+    if matched {
+      return Ok(());
+    }
+  }
+  Err(ApiError::new(
+    StatusCode::UNAUTHORIZED,
+    "Invalid or missing authorization token",
+    "invalid_request_error",
+  ))
+}
+
+pub fn bearer_token(req: &HttpRequest) -> Option<&str> {
+  req
+    .headers()
+    .get("authorization")?
+    .to_str()
+    .ok()?
+    .strip_prefix("Bearer ")
+}
+
+/// Token bucket holding up to `rate_limit` requests, refilled continuously
+/// over a minute.
+pub struct Bucket {
+  tokens: f64,
+  updated: Instant,
+}
+
+/// Takes one request from the bucket of the caller's token and, when the
+/// request names a `user`, from that user's bucket within the token too, so
+/// new users cannot get past the token's limit. Must run after
+/// `verify_bearer_token`, so buckets only exist for configured tokens.
+pub fn check_rate_limit(
+  req: &HttpRequest,
+  context: &Context,
+  user: Option<&str>,
+) -> Result<(), ApiError> {
+  let (Some(limit), Some(token)) =
+    (context.settings().rate_limit, bearer_token(req))
+  else {
+    return Ok(());
+  };
+  let capacity = limit as f64;
+  let per_sec = capacity / 60.0;
+  let now = Instant::now();
+  let mut keys = vec![token.to_string()];
+  if let Some(user) = user {
+    keys.push(format!("{}\0{}", token, user));
+  }
+  let mut buckets = context.buckets.lock().unwrap();
+  if keys.iter().any(|key| !buckets.contains_key(key)) {
+    // Users are chosen by clients, so drop the buckets that have refilled
+    // rather than keeping one per user ever seen.
+    buckets.retain(|_, bucket| {
+      now.duration_since(bucket.updated) < Duration::from_secs(60)
+    });
+  }
+  for key in &keys {
+    let bucket = buckets.entry(key.clone()).or_insert(Bucket {
+      tokens: capacity,
+      updated: now,
+    });
+    bucket.tokens = (bucket.tokens
+      + now.duration_since(bucket.updated).as_secs_f64() * per_sec)
+      .min(capacity);
+    bucket.updated = now;
+  }
+  // The emptiest bucket decides, and none is taken from unless all allow.
+  let lowest = keys
+    .iter()
+    .map(|key| buckets[key].tokens)
+    .fold(f64::INFINITY, f64::min);
+  if lowest >= 1.0 {
+    for key in &keys {
+      buckets.get_mut(key).unwrap().tokens -= 1.0;
+    }
+    return Ok(());
+  }
+  let retry_after = ((1.0 - lowest) / per_sec).ceil() as u64;
+  tracing::warn!(retry_after, user, "rate limit exceeded");
+  context.metrics.record_failure("rate_limit");
+  Err(ApiError::rate_limited(
+    "Rate limit exceeded, retry later",
+    retry_after,
+  ))
+}
+
+/// Compares two byte strings without short-circuiting on the first
+/// difference, so the comparison time does not reveal how much of a token
+/// matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  let mut diff = a.len() ^ b.len();
+  for i in 0..a.len().max(b.len()) {
+    let x = a.get(i).copied().unwrap_or(0);
+    let y = b.get(i).copied().unwrap_or(0);
+    diff |= (x ^ y) as usize;
+  }
+  diff == 0
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use actix_web::test::TestRequest;
+  use actix_web::ResponseError;
+
+  fn test_context(rate_limit: u32) -> Context {
+    Context::for_tests(&format!(
+      r#"
+        port = 8080
+        token = "t,u"
+        binary_path = "/bin/true"
+        models_dir = "/tmp"
+        rate_limit = {}
+      "#,
+      rate_limit
+    ))
+  }
+
+  fn request(token: &str) -> HttpRequest {
+    TestRequest::default()
+      .insert_header(("authorization", format!("Bearer {}", token)))
+      .to_http_request()
+  }
+
+  #[test]
+  fn verifies_any_configured_token() {
+    let tokens = ["t".to_string(), "u".to_string()];
+    assert!(verify_bearer_token(&request("t"), &tokens).is_ok());
+    assert!(verify_bearer_token(&request("u"), &tokens).is_ok());
+    assert!(verify_bearer_token(&request("v"), &tokens).is_err());
+    let anonymous = TestRequest::default().to_http_request();
+    assert!(verify_bearer_token(&anonymous, &tokens).is_err());
+  }
+
+  #[test]
+  fn limits_each_token_separately() {
+    let context = test_context(2);
+    assert!(check_rate_limit(&request("t"), &context, None).is_ok());
+    assert!(check_rate_limit(&request("t"), &context, None).is_ok());
+    let error = check_rate_limit(&request("t"), &context, None).unwrap_err();
+    assert_eq!(error.status_code(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(check_rate_limit(&request("u"), &context, None).is_ok());
+  }
+
+  #[test]
+  fn new_users_do_not_get_past_the_token_limit() {
+    let context = test_context(2);
+    assert!(check_rate_limit(&request("t"), &context, Some("a")).is_ok());
+    assert!(check_rate_limit(&request("t"), &context, Some("b")).is_ok());
+    assert!(check_rate_limit(&request("t"), &context, Some("c")).is_err());
+    assert!(check_rate_limit(&request("t"), &context, None).is_err());
+  }
+
+  #[test]
+  fn unlimited_without_rate_limit() {
+    let context = Context::for_tests(
+      r#"
+        port = 8080
+        token = "t"
+        binary_path = "/bin/true"
+        models_dir = "/tmp"
+      "#,
+    );
+    for _ in 0..100 {
+      assert!(check_rate_limit(&request("t"), &context, None).is_ok());
+    }
+  }
+}
@@ -0,0 +1,217 @@
+//! Circuit breaker failing generations fast while the binary keeps failing,
+//! such as after a broken install or a GPU driver crash.
+
+use crate::error::ApiError;
+use actix_web::http::StatusCode;
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Opens after `threshold` consecutive binary failures within `window`.
+/// While open, generations fail with a 503 without running the binary.
+/// After `cooldown` a single trial run is let through, which closes the
+/// circuit if it succeeds and opens it again if it fails.
+pub struct CircuitBreaker {
+  /// Disabled when 0.
+  pub threshold: u32,
+  pub window: Duration,
+  pub cooldown: Duration,
+  state: Mutex<BreakerState>,
+}
+
+#[derive(Default)]
+struct BreakerState {
+  failures: u32,
+  first_failure: Option<Instant>,
+  opened_at: Option<Instant>,
+  /// When the trial run of a half-open circuit started. A trial that never
+  /// reports, because its request was dropped, gives way to another after
+  /// `cooldown`.
+  trial_started: Option<Instant>,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerStatus {
+  Closed,
+  Open,
+  HalfOpen,
+}
+
+impl CircuitBreaker {
+  pub fn new(threshold: u32, window: Duration, cooldown: Duration) -> Self {
+    CircuitBreaker {
+      threshold,
+      window,
+      cooldown,
+      state: Mutex::new(BreakerState::default()),
+    }
+  }
+
+  pub fn status(&self) -> BreakerStatus {
+    let state = self.state.lock().unwrap();
+    match state.opened_at {
+      None => BreakerStatus::Closed,
+      Some(opened_at) if opened_at.elapsed() < self.cooldown => {
+        BreakerStatus::Open
+      }
+      Some(_) => BreakerStatus::HalfOpen,
+    }
+  }
+
+  /// Fails while the circuit is open, before a request waits in the queue.
+  /// Half-open circuits let requests through to compete for the trial.
+  pub fn check(&self) -> Result<(), ApiError> {
+    let state = self.state.lock().unwrap();
+    match state.opened_at {
+      Some(opened_at) if opened_at.elapsed() < self.cooldown => {
+        Err(self.open_error(opened_at))
+      }
+      _ => Ok(()),
+    }
+  }
+
+  /// Lets a binary run start, claiming the trial of a half-open circuit.
+  pub fn admit(&self) -> Result<(), ApiError> {
+    let mut state = self.state.lock().unwrap();
+    let Some(opened_at) = state.opened_at else {
+      return Ok(());
+    };
+    if opened_at.elapsed() < self.cooldown {
+      return Err(self.open_error(opened_at));
+    }
+    if state
+      .trial_started
+      .is_some_and(|started| started.elapsed() < self.cooldown)
+    {
+      return Err(self.open_error(Instant::now()));
+    }
+    tracing::info!("circuit breaker half-open, running a trial generation");
+    state.trial_started = Some(Instant::now());
+    Ok(())
+  }
+
+  /// Records the outcome of a binary run.
+  pub fn record(&self, success: bool) {
+    if self.threshold == 0 {
+      return;
+    }
+    let mut state = self.state.lock().unwrap();
+    if success {
+      if state.opened_at.is_some() {
+        tracing::info!("circuit breaker closed");
+      }
+      *state = BreakerState::default();
+      return;
+    }
+    if state.opened_at.is_some() {
+      if state.trial_started.take().is_some() {
+        tracing::warn!("circuit breaker trial failed, opening again");
+        state.opened_at = Some(Instant::now());
+      }
+      return;
+    }
+    let now = Instant::now();
+    if state
+      .first_failure
+      .is_none_or(|first| now.duration_since(first) > self.window)
+    {
+      state.first_failure = Some(now);
+      state.failures = 0;
+    }
+    state.failures += 1;
+    if state.failures >= self.threshold {
+      tracing::error!(
+        failures = state.failures,
+        cooldown = ?self.cooldown,
+        "circuit breaker opened"
+      );
+      state.opened_at = Some(now);
+    }
+  }
+
+  fn open_error(&self, opened_at: Instant) -> ApiError {
+    let retry_after = self.cooldown.saturating_sub(opened_at.elapsed());
+    ApiError::new(
+      StatusCode::SERVICE_UNAVAILABLE,
+      "Image generation is failing repeatedly, retry later",
+      "service_unavailable",
+    )
+    .with_retry_after(retry_after.as_secs_f64().ceil() as u64)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn breaker(cooldown: Duration) -> CircuitBreaker {
+    CircuitBreaker::new(3, Duration::from_secs(60), cooldown)
+  }
+
+  #[test]
+  fn opens_after_consecutive_failures() {
+    let breaker = breaker(Duration::from_secs(60));
+    breaker.record(false);
+    breaker.record(false);
+    assert!(breaker.status() == BreakerStatus::Closed);
+    assert!(breaker.check().is_ok());
+    breaker.record(false);
+    assert!(breaker.status() == BreakerStatus::Open);
+    let error = breaker.check().unwrap_err();
+    assert_eq!(error.error_type(), "service_unavailable");
+    assert!(breaker.admit().is_err());
+  }
+
+  #[test]
+  fn successes_reset_the_count() {
+    let breaker = breaker(Duration::from_secs(60));
+    breaker.record(false);
+    breaker.record(false);
+    breaker.record(true);
+    breaker.record(false);
+    breaker.record(false);
+    assert!(breaker.status() == BreakerStatus::Closed);
+  }
+
+  #[test]
+  fn half_open_lets_a_single_trial_through() {
+    let breaker = breaker(Duration::ZERO);
+    for _ in 0..3 {
+      breaker.record(false);
+    }
+    assert!(breaker.status() == BreakerStatus::HalfOpen);
+    assert!(breaker.check().is_ok());
+    assert!(breaker.admit().is_ok());
+    // A zero cooldown lets the next trial through at once, so use the
+    // state directly to check the claim.
+    assert!(breaker.state.lock().unwrap().trial_started.is_some());
+    breaker.record(true);
+    assert!(breaker.status() == BreakerStatus::Closed);
+  }
+
+  #[test]
+  fn failed_trial_opens_again() {
+    let breaker =
+      CircuitBreaker::new(1, Duration::from_secs(60), Duration::from_secs(60));
+    breaker.record(false);
+    // Pretend the cooldown is over.
+    breaker.state.lock().unwrap().opened_at =
+      Some(Instant::now() - Duration::from_secs(61));
+    assert!(breaker.status() == BreakerStatus::HalfOpen);
+    assert!(breaker.admit().is_ok());
+    assert!(breaker.admit().is_err());
+    breaker.record(false);
+    assert!(breaker.status() == BreakerStatus::Open);
+  }
+
+  #[test]
+  fn disabled_with_a_zero_threshold() {
+    let breaker =
+      CircuitBreaker::new(0, Duration::from_secs(60), Duration::from_secs(60));
+    for _ in 0..10 {
+      breaker.record(false);
+    }
+    assert!(breaker.status() == BreakerStatus::Closed);
+  }
+}
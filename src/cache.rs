@@ -0,0 +1,95 @@
+//! On-disk cache of generated images, keyed by every input that affects
+//! the output.
+
+use crate::api::ImageGenerationRequest;
+use crate::config::Context;
+use crate::generation::{
+  effective_cfg_scale, effective_sampler, effective_steps, ResolvedRequest,
+};
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Filename prefix of cached results in `cache_dir`.
+pub const RESULT_CACHE_PREFIX: &str = "sd_cache_";
+
+/// Hash of every parameter that affects the generated image.
+pub fn cache_key(
+  context: &Context,
+  body: &ImageGenerationRequest,
+  resolved: &ResolvedRequest,
+  seed: i64,
+) -> String {
+  let normalized = serde_json::json!({
+    "model": resolved.model,
+    "binary": resolved.binary,
+    "prompt": resolved.prompt,
+    "negative_prompt": resolved.negative_prompt,
+    "size": body.size,
+    "steps": effective_steps(context, body),
+    "cfg_scale": effective_cfg_scale(context, body),
+    "seed": seed,
+    "sampler": effective_sampler(body),
+    "deterministic": body.deterministic,
+    "clip_skip": body.clip_skip,
+    "vae_tiling": body.vae_tiling,
+    "diffusion_fa": body.diffusion_fa,
+    "vae_on_cpu": body.vae_on_cpu,
+    "tiling": body.tiling,
+    "strength": resolved.strength,
+    "refiner": resolved.refiner,
+    "refiner_switch_at": body.refiner_switch_at,
+    "extra_args": resolved.extra_args,
+  });
+  format!("{:x}", Sha256::digest(normalized.to_string()))
+}
+
+pub async fn read_cached_result(cache_path: &str) -> Option<Vec<u8>> {
+  let image_data = tokio::fs::read(cache_path).await.ok()?;
+  // Bump the modification time so eviction drops least recently used
+  // results first.
+  if let Ok(file) = std::fs::File::options().write(true).open(cache_path) {
+    let _ = file.set_modified(SystemTime::now());
+  }
+  Some(image_data)
+}
+
+/// Stores a result, then evicts least recently used results until the cache
+/// fits in `cache_max_bytes`.
+pub async fn store_cached_result(
+  context: &Context,
+  cache_path: &str,
+  image_data: &[u8],
+) {
+  if let Err(e) = tokio::fs::write(cache_path, image_data).await {
+    tracing::error!(error = %e, "failed to store cached result");
+    return;
+  }
+
+  let Ok(mut entries) = tokio::fs::read_dir(&context.cache_dir).await else {
+    return;
+  };
+  let mut cached = Vec::new();
+  while let Ok(Some(entry)) = entries.next_entry().await {
+    if !entry
+      .file_name()
+      .to_string_lossy()
+      .starts_with(RESULT_CACHE_PREFIX)
+    {
+      continue;
+    }
+    if let Ok(metadata) = entry.metadata().await {
+      let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+      cached.push((modified, metadata.len(), entry.path()));
+    }
+  }
+  cached.sort();
+  let mut total: u64 = cached.iter().map(|(_, len, _)| len).sum();
+  for (_, len, path) in cached {
+    if total <= context.cache_max_bytes {
+      break;
+    }
+    tracing::info!(path = ?path, "evicting cached result");
+    let _ = tokio::fs::remove_file(&path).await;
+    total -= len;
+  }
+}
@@ -0,0 +1,970 @@
+//! Server configuration, loaded from the environment and an optional
+//! config file.
+
+use crate::auth::Bucket;
+use crate::breaker::CircuitBreaker;
+use crate::generation::{parse_size, MIN_DIMENSION};
+use crate::idempotency::IdempotencyEntry;
+use crate::jobs::{Cancellations, Job};
+use crate::metrics::Metrics;
+use crate::persistent::WarmProcesses;
+use crate::queue::GenerationQueue;
+use crate::selftest::SelfTestStatus;
+use crate::storage::Storage;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::atomic::AtomicUsize;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+#[derive(Clone)]
+pub struct Context {
+  pub port: u16,
+  pub tokens: Vec<String>,
+  /// Binary run for requests without a `backend`.
+  pub binary_path: String,
+  /// Builds of the binary by backend name, such as `cuda` or `cpu`, which
+  /// requests pick with `backend`.
+  pub binaries: BTreeMap<String, String>,
+  /// Backend whose binary is `binary_path`, replacing `SD_CPP_SERVER_BINARY`.
+  pub default_backend: Option<String>,
+  pub diffusion: bool,
+  pub args: Option<Vec<String>>,
+  /// Command removing the background of generated images, run with the
+  /// input and output PNG paths appended, such as `rembg i`. Requests for
+  /// `background: "transparent"` are rejected when unset.
+  pub background_remover: Option<Vec<String>>,
+  /// Flags a request may pass in `extra_args`. None are allowed when unset.
+  pub allowed_extra_flags: Option<Vec<String>>,
+  pub force_scale: Option<i32>,
+  pub max_images: u32,
+  /// Largest accepted JSON body. Edits carry base64 images, well above
+  /// actix's 32 KiB default.
+  pub max_body_bytes: usize,
+  /// Default `--threads` when a request sets none. Every concurrent
+  /// generation uses this many, so keep `threads * max_concurrency` within
+  /// the host's cores.
+  pub threads: Option<u32>,
+  /// One permit per GPU, so each device runs at most one generation. Empty
+  /// unless `SD_CPP_SERVER_GPU_COUNT` is set.
+  pub devices: Arc<Vec<Arc<tokio::sync::Semaphore>>>,
+  pub max_steps: u32,
+  /// Seeds a single `/v1/images/sweep` may span.
+  pub max_sweep_seeds: u32,
+  /// Steps run for `quality: "hd"`.
+  pub hd_steps: u32,
+  /// Replaced by `POST /v1/admin/reload`, see `Context::settings`.
+  pub settings: Arc<RwLock<Settings>>,
+  /// Bearer token of the `/v1/admin` endpoints, which are disabled when
+  /// unset.
+  pub admin_token: Option<String>,
+  /// Longest accepted prompt or negative prompt, in characters.
+  pub max_prompt_chars: usize,
+  /// Largest accepted width or height.
+  pub max_dimension: u32,
+  /// Largest accepted `width * height`, in millions of pixels. A closer
+  /// measure of GPU memory use than `max_dimension`.
+  pub max_megapixels: Option<f64>,
+  /// Rejects sizes far from a model's `native_sizes` instead of warning.
+  pub strict_sizes: bool,
+  /// Local directory of the models. With `models_store`, it caches the
+  /// models downloaded from the store.
+  pub models_dir: String,
+  /// Set when `SD_CPP_SERVER_MODELS` is a URL such as `s3://bucket/prefix`.
+  pub models_store: Option<Arc<Storage>>,
+  pub cache_dir: String,
+  pub public_url: Option<String>,
+  /// Where URL outputs are uploaded instead of `cache_dir`, and the public
+  /// base URL they are then served from. `image_ttl` does not apply, so
+  /// expire them with the bucket's lifecycle rules.
+  pub output_store: Option<(Arc<Storage>, String)>,
+  pub image_ttl: Duration,
+  /// Age after which files left in `cache_dir` by a previous run are removed
+  /// at startup. Keep it above the longest generation when instances share
+  /// the directory.
+  pub stale_age: Duration,
+  /// Debugging aid leaving the binary's outputs in `cache_dir` until they
+  /// expire like URL outputs. Not for production, as disk use grows with
+  /// every generation.
+  pub keep_outputs: bool,
+  pub timeout: Option<Duration>,
+  /// Extra attempts for a generation failing with a transient error.
+  pub retries: u32,
+  pub queue: Arc<GenerationQueue>,
+  pub queue_mode: QueueMode,
+  pub queue_wait: Option<Duration>,
+  pub queue_threshold: usize,
+  /// Requests that can wait for a slot; more fail fast with a 503.
+  pub max_queue_depth: Option<usize>,
+  pub breaker: Arc<CircuitBreaker>,
+  pub loras_dir: Option<String>,
+  pub vaes_dir: Option<String>,
+  /// ControlNet models for `/v1/images/controlnet`.
+  pub controlnets_dir: Option<String>,
+  /// ESRGAN models for `/v1/images/upscale`.
+  pub upscalers_dir: Option<String>,
+  pub cache_results: bool,
+  pub cache_max_bytes: u64,
+  pub metrics: Arc<Metrics>,
+  /// When set, `/metrics` requires this bearer token instead of being open.
+  pub metrics_token: Option<String>,
+  pub shutdown_grace: Duration,
+  /// Requests being handled until their response is sent, which shutdown
+  /// waits for.
+  pub in_flight: Arc<AtomicUsize>,
+  /// Binaries seen to launch, so readiness checks only run each until
+  /// then.
+  pub binary_launched: Arc<Mutex<HashSet<String>>>,
+  /// Output of `binary --help` by binary, once it has run, to detect
+  /// optional flags.
+  pub binary_help: Arc<Mutex<HashMap<String, String>>>,
+  /// Keeps models loaded in warm processes between generations, for
+  /// binaries that support it. Others spawn a process per generation.
+  pub keep_alive: bool,
+  pub warm_processes: Arc<WarmProcesses>,
+  /// What `binary --version` printed at startup, if it reports a version.
+  pub binary_version: Option<String>,
+  /// The same for each of the `binaries`, by backend.
+  pub backend_versions: BTreeMap<String, Option<String>>,
+  /// Adds GPU memory from `nvidia-smi` to readiness checks.
+  pub gpu_monitor: bool,
+  /// Model of the generation that must pass at startup before readiness
+  /// checks succeed.
+  pub self_test_model: Option<String>,
+  pub self_test_timeout: Duration,
+  /// Unset without `self_test_model`.
+  pub self_test: Arc<Mutex<Option<SelfTestStatus>>>,
+  /// Models generated with since startup, whose weights are likely in the
+  /// page cache.
+  pub warm_models: Arc<Mutex<BTreeSet<String>>>,
+  /// SHA-256 of model files by path.
+  pub model_hashes: Arc<Mutex<HashMap<String, ModelHash>>>,
+  /// When `models_dir` was last checked, and whether it was readable.
+  pub models_dir_checked: Arc<Mutex<Option<(Instant, bool)>>>,
+  /// Origins allowed to call the API from a browser, or `*` for any. CORS
+  /// is disabled when unset.
+  pub cors_origins: Option<Vec<String>>,
+  /// Jobs of `/v1/images/generations/async` by ID, kept for `job_ttl` once
+  /// finished.
+  pub jobs: Arc<Mutex<HashMap<String, Job>>>,
+  pub job_ttl: Duration,
+  /// Hosts that `webhook_url` may point to. Webhooks are disabled when
+  /// unset.
+  pub webhook_allowed_hosts: Option<Vec<String>>,
+  pub http: reqwest::Client,
+  /// Running generations by ID, see `Cancellation`.
+  pub cancellations: Arc<Mutex<Cancellations>>,
+  pub buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+  /// How long responses are kept for `Idempotency-Key` retries. Keys are
+  /// ignored when unset.
+  pub idempotency_ttl: Option<Duration>,
+  /// Requests by token, path and `Idempotency-Key`.
+  pub idempotency: Arc<Mutex<HashMap<String, IdempotencyEntry>>>,
+}
+
+/// A model file's SHA-256, with the modification time and size it was
+/// computed for.
+pub struct ModelHash {
+  pub modified: SystemTime,
+  pub len: u64,
+  pub sha256: String,
+}
+
+/// Settings that can change while the server runs.
+pub struct Settings {
+  /// Presets selectable with a request's `style`, by name.
+  pub styles: BTreeMap<String, Style>,
+  /// Negative prompt used when a request has none.
+  pub default_negative_prompt: Option<String>,
+  /// When set, only these models can be requested or are listed.
+  pub allowed_models: Option<Vec<String>>,
+  /// Generation requests allowed per token and minute.
+  pub rate_limit: Option<u32>,
+  /// Resolutions models were trained at, by model name or by a name prefix
+  /// ending in `*`.
+  pub native_sizes: BTreeMap<String, Vec<(u32, u32)>>,
+}
+
+impl Settings {
+  /// Loads the settings alone, from the same sources as `Context::load`,
+  /// leaving the rest of the configuration unchecked.
+  pub fn load() -> Result<Self, ConfigError> {
+    let mut source = ConfigSource::new();
+    let settings = Self::from_source(&mut source);
+    match source.errors.is_empty() {
+      true => Ok(settings),
+      false => Err(ConfigError(source.errors)),
+    }
+  }
+
+  fn from_source(source: &mut ConfigSource) -> Self {
+    Settings {
+      styles: source.styles(),
+      native_sizes: source.native_sizes(),
+      default_negative_prompt: source
+        .parse::<String>(
+          "SD_CPP_SERVER_DEFAULT_NEGATIVE_PROMPT",
+          "default_negative_prompt",
+        )
+        .filter(|prompt| !prompt.is_empty()),
+      allowed_models: source.list(
+        "SD_CPP_SERVER_ALLOWED_MODELS",
+        "allowed_models",
+        ',',
+      ),
+      rate_limit: source
+        .parse("SD_CPP_SERVER_RATE_LIMIT", "rate_limit")
+        .filter(|n| *n > 0),
+    }
+  }
+}
+
+/// Names of the `Settings`, as reported by `POST /v1/admin/reload`.
+pub const RELOADABLE_SETTINGS: &[&str] = &[
+  "styles",
+  "default_negative_prompt",
+  "allowed_models",
+  "rate_limit",
+  "native_sizes",
+];
+
+/// Prompt fragments wrapped around the prompts of requests choosing this
+/// style, from a `[styles.<name>]` table of the config file.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Style {
+  pub prefix: Option<String>,
+  pub suffix: Option<String>,
+  /// Appended to the request's negative prompt.
+  pub negative: Option<String>,
+}
+
+/// What to do with a request when every generation slot is busy.
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueMode {
+  /// Wait for a slot, up to `queue_wait` if set.
+  Queue,
+  /// Fail with a 429 once more than `queue_threshold` requests are waiting.
+  Reject,
+}
+
+impl Context {
+  /// The current settings. Copy what is needed out of the guard rather than
+  /// holding it across an `.await`.
+  pub fn settings(&self) -> RwLockReadGuard<'_, Settings> {
+    self.settings.read().unwrap()
+  }
+
+  /// The binary of `backend`, or the default one. Backends are checked
+  /// by `validate_request`.
+  pub fn binary(&self, backend: Option<&str>) -> &str {
+    backend
+      .and_then(|backend| self.binaries.get(backend))
+      .unwrap_or(&self.binary_path)
+  }
+
+  /// Replaces the settings with freshly loaded ones.
+  pub fn reload_settings(&self, settings: Settings) {
+    *self.settings.write().unwrap() = settings;
+  }
+
+  pub fn is_model_allowed(&self, name: &str) -> bool {
+    self
+      .settings()
+      .allowed_models
+      .as_ref()
+      .is_none_or(|allowed| allowed.iter().any(|model| model == name))
+  }
+
+  /// Loads the configuration from `SD_CPP_SERVER_*` environment variables,
+  /// falling back to the keys of the TOML file named by
+  /// `SD_CPP_SERVER_CONFIG`. Every missing or invalid setting is reported at
+  /// once.
+  pub fn load() -> Result<Self, ConfigError> {
+    Self::from_source(ConfigSource::new())
+  }
+
+  /// A context configured by `config` alone, as a TOML config file.
+  #[cfg(test)]
+  pub fn for_tests(config: &str) -> Self {
+    let source = ConfigSource {
+      file: config.parse().expect("invalid test config"),
+      errors: Vec::new(),
+      env: false,
+    };
+    Self::from_source(source).expect("invalid test config")
+  }
+
+  fn from_source(mut source: ConfigSource) -> Result<Self, ConfigError> {
+    let secs = |secs: u64| Duration::from_secs(secs);
+    let cache_dir: String = source
+      .parse("SD_CPP_SERVER_CACHE", "cache_dir")
+      .unwrap_or_else(|| "/tmp".to_string());
+    let models: String = source
+      .required("SD_CPP_SERVER_MODELS", "models_dir")
+      .unwrap_or_default();
+    let (models_dir, models_store) = if Storage::is_remote(&models) {
+      let models_dir = source
+        .parse("SD_CPP_SERVER_MODELS_CACHE", "models_cache_dir")
+        .unwrap_or_else(|| format!("{}/models", cache_dir));
+      (models_dir, source.storage("SD_CPP_SERVER_MODELS", &models))
+    } else {
+      (models, None)
+    };
+    let binaries = source.binaries();
+    let default_backend: Option<String> =
+      source.parse("SD_CPP_SERVER_DEFAULT_BACKEND", "default_backend");
+    let binary_path = match &default_backend {
+      Some(backend) => binaries.get(backend).cloned().unwrap_or_else(|| {
+        source.errors.push(format!(
+          "SD_CPP_SERVER_DEFAULT_BACKEND (default_backend): '{}' is not in \
+           SD_CPP_SERVER_BINARIES",
+          backend
+        ));
+        String::new()
+      }),
+      None => source
+        .required("SD_CPP_SERVER_BINARY", "binary_path")
+        .unwrap_or_default(),
+    };
+    let context = Context {
+      port: source.required("SD_CPP_SERVER_PORT", "port").unwrap_or(0),
+      // Comma-separated so keys can be rotated; empty entries are ignored.
+      tokens: source
+        .required_list("SD_CPP_SERVER_TOKEN", "token", ',')
+        .unwrap_or_default(),
+      binary_path,
+      binaries,
+      default_backend,
+      diffusion: source.flag("SD_CPP_SERVER_DIFFUSION", "diffusion"),
+      args: source.words("SD_CPP_SERVER_ARGS", "args"),
+      background_remover: source
+        .words("SD_CPP_SERVER_BACKGROUND_REMOVER", "background_remover"),
+      allowed_extra_flags: source.list(
+        "SD_CPP_SERVER_ALLOWED_EXTRA_FLAGS",
+        "allowed_extra_flags",
+        ',',
+      ),
+      force_scale: source.parse("SD_CPP_SERVER_FORCE_SCALE", "force_scale"),
+      max_images: source
+        .parse_min("SD_CPP_SERVER_MAX_IMAGES", "max_images", 1)
+        .unwrap_or(10),
+      max_body_bytes: source
+        .parse("SD_CPP_SERVER_MAX_BODY_BYTES", "max_body_bytes")
+        .unwrap_or(16 * 1024 * 1024),
+      threads: source.parse("SD_CPP_SERVER_THREADS", "threads"),
+      devices: Arc::new(
+        (0..source
+          .parse::<usize>("SD_CPP_SERVER_GPU_COUNT", "gpu_count")
+          .unwrap_or(0))
+          .map(|_| Arc::new(tokio::sync::Semaphore::new(1)))
+          .collect(),
+      ),
+      max_steps: source
+        .parse_min("SD_CPP_SERVER_MAX_STEPS", "max_steps", 1)
+        .unwrap_or(150),
+      max_sweep_seeds: source
+        .parse_min("SD_CPP_SERVER_MAX_SWEEP_SEEDS", "max_sweep_seeds", 1)
+        .unwrap_or(16),
+      hd_steps: source
+        .parse_min("SD_CPP_SERVER_HD_STEPS", "hd_steps", 1)
+        .unwrap_or(50),
+      max_prompt_chars: source
+        .parse_min("SD_CPP_SERVER_MAX_PROMPT_CHARS", "max_prompt_chars", 1)
+        .unwrap_or(4000),
+      settings: Arc::new(RwLock::new(Settings::from_source(&mut source))),
+      admin_token: source.parse("SD_CPP_SERVER_ADMIN_TOKEN", "admin_token"),
+      max_dimension: source
+        .parse_min(
+          "SD_CPP_SERVER_MAX_DIMENSION",
+          "max_dimension",
+          MIN_DIMENSION,
+        )
+        .unwrap_or(2048),
+      max_megapixels: source
+        .parse("SD_CPP_SERVER_MAX_MEGAPIXELS", "max_megapixels")
+        .filter(|megapixels| *megapixels > 0.0),
+      strict_sizes: source.flag("SD_CPP_SERVER_STRICT_SIZES", "strict_sizes"),
+      models_dir,
+      models_store,
+      cache_dir,
+      public_url: source
+        .parse::<String>("SD_CPP_SERVER_PUBLIC_URL", "public_url")
+        .map(|s| s.trim_end_matches('/').to_string()),
+      output_store: source.output_store(),
+      image_ttl: secs(
+        source
+          .parse("SD_CPP_SERVER_IMAGE_TTL_SECS", "image_ttl_secs")
+          .unwrap_or(3600),
+      ),
+      keep_outputs: source.flag("SD_CPP_SERVER_KEEP_OUTPUTS", "keep_outputs"),
+      stale_age: secs(
+        source
+          .parse("SD_CPP_SERVER_STALE_AGE_SECS", "stale_age_secs")
+          .unwrap_or(3600),
+      ),
+      timeout: source
+        .parse("SD_CPP_SERVER_TIMEOUT_SECS", "timeout_secs")
+        .map(secs),
+      retries: source
+        .parse("SD_CPP_SERVER_RETRIES", "retries")
+        .unwrap_or(0),
+      queue: Arc::new(GenerationQueue::new(
+        source
+          .parse_min("SD_CPP_SERVER_MAX_CONCURRENCY", "max_concurrency", 1)
+          .unwrap_or(1),
+      )),
+      queue_mode: match source.choice(
+        "SD_CPP_SERVER_QUEUE_MODE",
+        "queue_mode",
+        &["queue", "reject"],
+      ) {
+        Some("reject") => QueueMode::Reject,
+        _ => QueueMode::Queue,
+      },
+      queue_wait: source
+        .parse("SD_CPP_SERVER_QUEUE_WAIT_SECS", "queue_wait_secs")
+        .map(secs),
+      queue_threshold: source
+        .parse("SD_CPP_SERVER_QUEUE_THRESHOLD", "queue_threshold")
+        .unwrap_or(0),
+      breaker: Arc::new(CircuitBreaker::new(
+        source
+          .parse("SD_CPP_SERVER_BREAKER_THRESHOLD", "breaker_threshold")
+          .unwrap_or(5),
+        secs(
+          source
+            .parse("SD_CPP_SERVER_BREAKER_WINDOW_SECS", "breaker_window_secs")
+            .unwrap_or(60),
+        ),
+        secs(
+          source
+            .parse(
+              "SD_CPP_SERVER_BREAKER_COOLDOWN_SECS",
+              "breaker_cooldown_secs",
+            )
+            .unwrap_or(30),
+        ),
+      )),
+      max_queue_depth: source
+        .parse("SD_CPP_SERVER_MAX_QUEUE_DEPTH", "max_queue_depth"),
+      loras_dir: source.parse("SD_CPP_SERVER_LORAS", "loras_dir"),
+      vaes_dir: source.parse("SD_CPP_SERVER_VAES", "vaes_dir"),
+      controlnets_dir: source
+        .parse("SD_CPP_SERVER_CONTROLNETS", "controlnets_dir"),
+      upscalers_dir: source.parse("SD_CPP_SERVER_UPSCALERS", "upscalers_dir"),
+      cache_results: source
+        .flag("SD_CPP_SERVER_CACHE_RESULTS", "cache_results"),
+      cache_max_bytes: source
+        .parse("SD_CPP_SERVER_CACHE_MAX_BYTES", "cache_max_bytes")
+        .unwrap_or(1024 * 1024 * 1024),
+      metrics: Arc::new(Metrics::default()),
+      metrics_token: source
+        .parse("SD_CPP_SERVER_METRICS_TOKEN", "metrics_token"),
+      shutdown_grace: secs(
+        source
+          .parse("SD_CPP_SERVER_SHUTDOWN_GRACE_SECS", "shutdown_grace_secs")
+          .unwrap_or(30),
+      ),
+      cors_origins: source.list(
+        "SD_CPP_SERVER_CORS_ORIGINS",
+        "cors_origins",
+        ',',
+      ),
+      jobs: Arc::new(Mutex::new(HashMap::new())),
+      job_ttl: secs(
+        source
+          .parse("SD_CPP_SERVER_JOB_TTL_SECS", "job_ttl_secs")
+          .unwrap_or(3600),
+      ),
+      webhook_allowed_hosts: source.list(
+        "SD_CPP_SERVER_WEBHOOK_ALLOWED_HOSTS",
+        "webhook_allowed_hosts",
+        ',',
+      ),
+      // Webhooks are checked against `webhook_allowed_hosts`, which a
+      // redirect would get around.
+      http: reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .expect("failed to build the HTTP client"),
+      cancellations: Arc::new(Mutex::new(HashMap::new())),
+      in_flight: Arc::new(AtomicUsize::new(0)),
+      buckets: Arc::new(Mutex::new(HashMap::new())),
+      idempotency_ttl: Some(secs(
+        source
+          .parse("SD_CPP_SERVER_IDEMPOTENCY_TTL_SECS", "idempotency_ttl_secs")
+          .unwrap_or(600),
+      ))
+      .filter(|ttl| !ttl.is_zero()),
+      idempotency: Arc::new(Mutex::new(HashMap::new())),
+      gpu_monitor: source.flag("SD_CPP_SERVER_GPU_MONITOR", "gpu_monitor"),
+      self_test_model: source
+        .parse("SD_CPP_SERVER_STARTUP_SELFTEST", "startup_selftest"),
+      self_test_timeout: secs(
+        source
+          .parse_min(
+            "SD_CPP_SERVER_STARTUP_SELFTEST_TIMEOUT_SECS",
+            "startup_selftest_timeout_secs",
+            1,
+          )
+          .unwrap_or(300),
+      ),
+      self_test: Arc::new(Mutex::new(None)),
+      binary_launched: Arc::new(Mutex::new(HashSet::new())),
+      binary_help: Arc::new(Mutex::new(HashMap::new())),
+      keep_alive: source.flag("SD_CPP_SERVER_KEEP_ALIVE", "keep_alive"),
+      // Each warm process holds its model in memory.
+      warm_processes: Arc::new(WarmProcesses::new(
+        source
+          .parse_min("SD_CPP_SERVER_KEEP_ALIVE_MODELS", "keep_alive_models", 1)
+          .unwrap_or(1),
+      )),
+      binary_version: None,
+      backend_versions: BTreeMap::new(),
+      models_dir_checked: Arc::new(Mutex::new(None)),
+      warm_models: Arc::new(Mutex::new(BTreeSet::new())),
+      model_hashes: Arc::new(Mutex::new(HashMap::new())),
+    };
+    if source.errors.is_empty() {
+      Ok(context)
+    } else {
+      Err(ConfigError(source.errors))
+    }
+  }
+}
+
+/// Every problem found while loading the configuration.
+#[derive(Debug)]
+pub struct ConfigError(Vec<String>);
+
+impl ConfigError {
+  pub fn errors(&self) -> &[String] {
+    &self.0
+  }
+}
+
+impl std::fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    writeln!(f, "Invalid configuration:")?;
+    for error in &self.0 {
+      writeln!(f, "  - {}", error)?;
+    }
+    Ok(())
+  }
+}
+
+/// Looks settings up in the environment first, then in the config file,
+/// collecting errors instead of failing on the first one.
+struct ConfigSource {
+  file: toml::Table,
+  errors: Vec<String>,
+  /// Unset for tests, so they only see their own config.
+  env: bool,
+}
+
+impl ConfigSource {
+  pub fn new() -> Self {
+    let mut source = ConfigSource {
+      file: toml::Table::new(),
+      errors: Vec::new(),
+      env: true,
+    };
+    if let Ok(path) = std::env::var("SD_CPP_SERVER_CONFIG") {
+      match std::fs::read_to_string(&path) {
+        Ok(contents) => match contents.parse::<toml::Table>() {
+          Ok(file) => source.file = file,
+          Err(e) => source.errors.push(format!("{}: {}", path, e)),
+        },
+        Err(e) => source.errors.push(format!("{}: {}", path, e)),
+      }
+    }
+    source
+  }
+
+  fn var(&self, env: &str) -> Option<String> {
+    self.env.then(|| std::env::var(env).ok()).flatten()
+  }
+
+  pub fn raw(&self, env: &str, key: &str) -> Option<String> {
+    if let Some(value) = self.var(env) {
+      return Some(value);
+    }
+    match self.file.get(key)? {
+      toml::Value::String(value) => Some(value.clone()),
+      // Same convention as the environment flags.
+      toml::Value::Boolean(value) => {
+        Some(if *value { "1" } else { "0" }.into())
+      }
+      value => Some(value.to_string()),
+    }
+  }
+
+  pub fn parse<T>(&mut self, env: &str, key: &str) -> Option<T>
+  where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+  {
+    let raw = self.raw(env, key)?;
+    match raw.parse() {
+      Ok(value) => Some(value),
+      Err(e) => {
+        self
+          .errors
+          .push(format!("{} ({}): invalid value '{}': {}", env, key, raw, e));
+        None
+      }
+    }
+  }
+
+  pub fn required<T>(&mut self, env: &str, key: &str) -> Option<T>
+  where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+  {
+    if self.raw(env, key).is_none() {
+      self.errors.push(format!("{} ({}) is not set", env, key));
+    }
+    self.parse(env, key)
+  }
+
+  /// Same as `parse`, but values below `min` are reported as invalid.
+  pub fn parse_min<T>(&mut self, env: &str, key: &str, min: T) -> Option<T>
+  where
+    T: std::str::FromStr + PartialOrd + std::fmt::Display,
+    T::Err: std::fmt::Display,
+  {
+    let value = self.parse(env, key)?;
+    if value < min {
+      self
+        .errors
+        .push(format!("{} ({}): must be at least {}", env, key, min));
+      return None;
+    }
+    Some(value)
+  }
+
+  /// One of `choices`, which are the only accepted values.
+  pub fn choice(
+    &mut self,
+    env: &str,
+    key: &str,
+    choices: &[&'static str],
+  ) -> Option<&'static str> {
+    let raw = self.raw(env, key)?;
+    let choice = choices.iter().find(|choice| **choice == raw).copied();
+    if choice.is_none() {
+      self.errors.push(format!(
+        "{} ({}): invalid value '{}', expected one of: {}",
+        env,
+        key,
+        raw,
+        choices.join(", ")
+      ));
+    }
+    choice
+  }
+
+  /// Opens the store at `url`, reporting failures against `env`.
+  pub fn storage(&mut self, env: &str, url: &str) -> Option<Arc<Storage>> {
+    match Storage::open(url) {
+      Ok(storage) => Some(Arc::new(storage)),
+      Err(e) => {
+        self
+          .errors
+          .push(format!("{}: invalid store '{}': {}", env, url, e));
+        None
+      }
+    }
+  }
+
+  /// `SD_CPP_SERVER_OUTPUT_STORE`, which needs the public URL of its
+  /// objects in `SD_CPP_SERVER_OUTPUT_STORE_URL`.
+  pub fn output_store(&mut self) -> Option<(Arc<Storage>, String)> {
+    let url: String =
+      self.parse("SD_CPP_SERVER_OUTPUT_STORE", "output_store")?;
+    let Some(public_url) = self
+      .parse::<String>("SD_CPP_SERVER_OUTPUT_STORE_URL", "output_store_url")
+    else {
+      self.errors.push(
+        "SD_CPP_SERVER_OUTPUT_STORE_URL (output_store_url) is not set"
+          .to_string(),
+      );
+      return None;
+    };
+    let storage = self.storage("SD_CPP_SERVER_OUTPUT_STORE", &url)?;
+    Some((storage, public_url.trim_end_matches('/').to_string()))
+  }
+
+  /// The `styles` tables of the config file. They have no environment
+  /// variable.
+  pub fn styles(&mut self) -> BTreeMap<String, Style> {
+    let Some(styles) = self.file.get("styles") else {
+      return BTreeMap::new();
+    };
+    match styles.clone().try_into() {
+      Ok(styles) => styles,
+      Err(e) => {
+        self.errors.push(format!("styles: {}", e));
+        BTreeMap::new()
+      }
+    }
+  }
+
+  /// The `native_sizes` table of the config file, such as
+  /// `"sdxl*" = ["1024x1024", "1152x896"]`. It has no environment variable.
+  pub fn native_sizes(&mut self) -> BTreeMap<String, Vec<(u32, u32)>> {
+    let Some(table) = self.file.get("native_sizes") else {
+      return BTreeMap::new();
+    };
+    let table: BTreeMap<String, Vec<String>> = match table.clone().try_into() {
+      Ok(table) => table,
+      Err(e) => {
+        self.errors.push(format!("native_sizes: {}", e));
+        return BTreeMap::new();
+      }
+    };
+    let mut native_sizes = BTreeMap::new();
+    for (model, sizes) in table {
+      match sizes
+        .iter()
+        .map(|size| parse_size(size))
+        .collect::<Option<Vec<_>>>()
+      {
+        Some(sizes) if !sizes.is_empty() => {
+          native_sizes.insert(model, sizes);
+        }
+        _ => self.errors.push(format!(
+          "native_sizes.{}: expected a list of WIDTHxHEIGHT sizes",
+          model
+        )),
+      }
+    }
+    native_sizes
+  }
+
+  /// Binaries by backend, given as `name=path` pairs separated by commas,
+  /// or as a `[binaries]` table of the config file.
+  pub fn binaries(&mut self) -> BTreeMap<String, String> {
+    let env = "SD_CPP_SERVER_BINARIES";
+    if self.var(env).is_none() {
+      if let Some(table @ toml::Value::Table(_)) = self.file.get("binaries") {
+        return match table.clone().try_into() {
+          Ok(binaries) => binaries,
+          Err(e) => {
+            self.errors.push(format!("binaries: {}", e));
+            BTreeMap::new()
+          }
+        };
+      }
+    }
+    let mut binaries = BTreeMap::new();
+    for entry in self.list(env, "binaries", ',').unwrap_or_default() {
+      match entry.split_once('=') {
+        Some((name, path)) if !name.trim().is_empty() => {
+          binaries.insert(name.trim().to_string(), path.trim().to_string());
+        }
+        _ => self.errors.push(format!(
+          "{} (binaries): expected name=path, got '{}'",
+          env, entry
+        )),
+      }
+    }
+    binaries
+  }
+
+  pub fn flag(&mut self, env: &str, key: &str) -> bool {
+    self.raw(env, key).as_deref() == Some("1")
+  }
+
+  /// A list given as a `separator`-separated string, or as an array in the
+  /// config file. Empty entries are dropped.
+  pub fn list(
+    &self,
+    env: &str,
+    key: &str,
+    separator: char,
+  ) -> Option<Vec<String>> {
+    let items: Vec<String> = match (self.var(env), self.file.get(key)) {
+      (Some(value), _) => value.split(separator).map(str::to_string).collect(),
+      (None, Some(toml::Value::Array(values))) => values
+        .iter()
+        .map(|value| match value {
+          toml::Value::String(value) => value.clone(),
+          value => value.to_string(),
+        })
+        .collect(),
+      (None, Some(_)) => self
+        .raw(env, key)?
+        .split(separator)
+        .map(str::to_string)
+        .collect(),
+      (None, None) => return None,
+    };
+    Some(
+      items
+        .into_iter()
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect(),
+    )
+  }
+
+  /// Command line arguments separated by any whitespace, or an array in the
+  /// config file.
+  pub fn words(&self, env: &str, key: &str) -> Option<Vec<String>> {
+    if let (None, Some(toml::Value::Array(_))) =
+      (self.var(env), self.file.get(key))
+    {
+      return self.list(env, key, ' ');
+    }
+    let raw = self.raw(env, key)?;
+    Some(raw.split_whitespace().map(str::to_string).collect())
+  }
+
+  pub fn required_list(
+    &mut self,
+    env: &str,
+    key: &str,
+    separator: char,
+  ) -> Option<Vec<String>> {
+    let list = self.list(env, key, separator);
+    if list.as_ref().is_none_or(|list| list.is_empty()) {
+      self.errors.push(format!("{} ({}) is not set", env, key));
+    }
+    list
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const BASE: &str = r#"
+    port = 8080
+    token = "t"
+    binary_path = "/bin/true"
+    models_dir = "/tmp"
+  "#;
+
+  #[test]
+  fn args_split_on_any_whitespace() {
+    let context =
+      Context::for_tests(&format!("{}args = \"-t  4\\t--vae-tiling \"", BASE));
+    assert_eq!(
+      context.args,
+      Some(vec![
+        "-t".to_string(),
+        "4".to_string(),
+        "--vae-tiling".to_string()
+      ])
+    );
+  }
+
+  #[test]
+  fn args_as_an_array_keep_spaces() {
+    let context = Context::for_tests(&format!(
+      "{}args = [\"--lora-model-dir\", \"/a b\"]",
+      BASE
+    ));
+    assert_eq!(
+      context.args,
+      Some(vec!["--lora-model-dir".to_string(), "/a b".to_string()])
+    );
+  }
+
+  /// Answers the first request to `listener` with `head`.
+  fn serve_once(listener: tokio::net::TcpListener, head: String) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    tokio::spawn(async move {
+      let (mut stream, _) = listener.accept().await.unwrap();
+      let _ = stream.read(&mut [0u8; 4096]).await;
+      let response =
+        format!("{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", head);
+      stream.write_all(response.as_bytes()).await.unwrap();
+    });
+  }
+
+  #[tokio::test]
+  async fn webhooks_do_not_follow_redirects() {
+    let target = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let redirect = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", redirect.local_addr().unwrap());
+    serve_once(
+      redirect,
+      format!(
+        "HTTP/1.1 307 Temporary Redirect\r\nLocation: http://{}/",
+        target.local_addr().unwrap()
+      ),
+    );
+    serve_once(target, "HTTP/1.1 200 OK".to_string());
+
+    let context = Context::for_tests(BASE);
+    let response = context.http.post(url).send().await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::TEMPORARY_REDIRECT);
+  }
+
+  #[test]
+  fn tokens_drop_empty_entries() {
+    let context = Context::for_tests(&BASE.replace("\"t\"", "\"t,, u \""));
+    assert_eq!(context.tokens, vec!["t".to_string(), "u".to_string()]);
+  }
+
+  #[test]
+  fn missing_settings_are_all_reported() {
+    let source = ConfigSource {
+      file: toml::Table::new(),
+      errors: Vec::new(),
+      env: false,
+    };
+    let Err(ConfigError(errors)) = Context::from_source(source) else {
+      panic!("loaded without required settings");
+    };
+    for setting in ["port", "token", "binary_path", "models_dir"] {
+      assert!(
+        errors.iter().any(|error| error.contains(setting)),
+        "{} not reported in {:?}",
+        setting,
+        errors
+      );
+    }
+  }
+
+  #[test]
+  fn malformed_settings_are_all_reported() {
+    let source = ConfigSource {
+      file: format!(
+        r#"{}
+          port = "eighty"
+          timeout_secs = "10m"
+          shutdown_grace_secs = -1
+          max_concurrency = 0
+        "#,
+        BASE.replace("port = 8080", "")
+      )
+      .parse()
+      .unwrap(),
+      errors: Vec::new(),
+      env: false,
+    };
+    let Err(ConfigError(errors)) = Context::from_source(source) else {
+      panic!("loaded with malformed settings");
+    };
+    assert_eq!(errors.len(), 4, "{:?}", errors);
+    for error in [
+      "SD_CPP_SERVER_PORT (port): invalid value 'eighty'",
+      "SD_CPP_SERVER_TIMEOUT_SECS (timeout_secs): invalid value '10m'",
+      "SD_CPP_SERVER_SHUTDOWN_GRACE_SECS (shutdown_grace_secs): invalid value \
+       '-1'",
+      "SD_CPP_SERVER_MAX_CONCURRENCY (max_concurrency): must be at least 1",
+    ] {
+      assert!(
+        errors.iter().any(|reported| reported.starts_with(error)),
+        "{} not reported in {:?}",
+        error,
+        errors
+      );
+    }
+  }
+}
@@ -0,0 +1,110 @@
+//! Error bodies returned by every endpoint.
+
+use crate::REQUEST_ID;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+use std::fmt;
+
+#[derive(Debug, Serialize)]
+pub struct ErrorResponse {
+  pub error: ErrorDetail,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorDetail {
+  pub message: String,
+  #[serde(rename = "type")]
+  pub error_type: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub request_id: Option<String>,
+}
+
+impl ErrorResponse {
+  pub fn new(message: impl Into<String>, error_type: &str) -> Self {
+    ErrorResponse {
+      error: ErrorDetail {
+        message: message.into(),
+        error_type: error_type.to_string(),
+        request_id: REQUEST_ID.try_with(Clone::clone).ok(),
+      },
+    }
+  }
+}
+
+/// A failed request, answered with an `ErrorResponse` body.
+#[derive(Debug)]
+pub struct ApiError {
+  status: StatusCode,
+  /// Seconds sent in a `Retry-After` header.
+  retry_after: Option<u64>,
+  body: ErrorResponse,
+}
+
+impl ApiError {
+  pub fn new(
+    status: StatusCode,
+    message: impl Into<String>,
+    error_type: &str,
+  ) -> Self {
+    ApiError {
+      status,
+      retry_after: None,
+      body: ErrorResponse::new(message, error_type),
+    }
+  }
+
+  pub fn bad_request(message: impl Into<String>) -> Self {
+    Self::new(StatusCode::BAD_REQUEST, message, "invalid_request_error")
+  }
+
+  pub fn not_found(message: impl Into<String>) -> Self {
+    Self::new(StatusCode::NOT_FOUND, message, "invalid_request_error")
+  }
+
+  pub fn server_error(message: impl Into<String>) -> Self {
+    Self::new(StatusCode::INTERNAL_SERVER_ERROR, message, "server_error")
+  }
+
+  pub fn rate_limited(message: impl Into<String>, retry_after: u64) -> Self {
+    Self::new(StatusCode::TOO_MANY_REQUESTS, message, "rate_limit")
+      .with_retry_after(retry_after)
+  }
+
+  pub fn with_retry_after(mut self, secs: u64) -> Self {
+    self.retry_after = Some(secs.max(1));
+    self
+  }
+
+  pub fn message(&self) -> &str {
+    &self.body.error.message
+  }
+
+  pub fn error_type(&self) -> &str {
+    &self.body.error.error_type
+  }
+}
+
+impl fmt::Display for ApiError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "{}: {}",
+      self.body.error.error_type, self.body.error.message
+    )
+  }
+}
+
+impl ResponseError for ApiError {
+  fn status_code(&self) -> StatusCode {
+    self.status
+  }
+
+  fn error_response(&self) -> HttpResponse {
+    let mut response = HttpResponse::build(self.status);
+    if let Some(retry_after) = self.retry_after {
+      response.insert_header(("Retry-After", retry_after.to_string()));
+    }
+    response.json(&self.body)
+  }
+}
@@ -0,0 +1,228 @@
+//! Files written to `cache_dir` and their cleanup.
+
+use crate::config::Context;
+use crate::error::ApiError;
+use actix_web::http::StatusCode;
+use std::time::Duration;
+
+/// Filename prefix of every image written to `cache_dir`. Files kept for
+/// `response_format: "url"` are only served and expired if they carry it.
+pub const OUTPUT_PREFIX: &str = "sd_output_";
+
+/// Distinguishes the files of one request in `cache_dir`. Timestamps alone
+/// collide between requests arriving in the same second.
+pub fn unique_name() -> String {
+  uuid::Uuid::new_v4().simple().to_string()
+}
+
+/// A file in `cache_dir` that is removed when dropped, so it is cleaned up on
+/// every error path and when a request is dropped mid-generation.
+pub struct TempFile {
+  path: String,
+  /// Logs the path instead of removing the file.
+  keep: bool,
+}
+
+impl TempFile {
+  pub fn new(path: String) -> Self {
+    TempFile { path, keep: false }
+  }
+
+  /// An image written by the binary, kept for inspection when
+  /// `keep_outputs` is set.
+  pub fn output(context: &Context, path: String) -> Self {
+    TempFile {
+      path,
+      keep: context.keep_outputs,
+    }
+  }
+
+  pub fn path(&self) -> &str {
+    &self.path
+  }
+
+  /// Keeps the file once it is complete, such as an output the response
+  /// links to.
+  pub fn persist(mut self) {
+    self.path.clear();
+  }
+}
+
+impl Drop for TempFile {
+  fn drop(&mut self) {
+    if self.path.is_empty() {
+      return;
+    }
+    if self.keep {
+      tracing::info!(path = %self.path, "keeping output file");
+    } else {
+      let _ = std::fs::remove_file(&self.path);
+    }
+  }
+}
+
+/// Fails unless a file can be written to `cache_dir`, so a read-only or full
+/// directory is reported as such instead of as a failed generation.
+pub async fn check_cache_dir(context: &Context) -> Result<(), ApiError> {
+  let probe = TempFile::new(format!(
+    "{}/{}probe_{}.tmp",
+    context.cache_dir,
+    OUTPUT_PREFIX,
+    unique_name()
+  ));
+  let Err(e) = tokio::fs::write(probe.path(), b"probe").await else {
+    return Ok(());
+  };
+  tracing::error!(error = %e, cache_dir = %context.cache_dir, "cache directory not writable");
+  context.metrics.record_failure("server_error");
+  Err(match e.kind() {
+    std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded => {
+      ApiError::new(
+        StatusCode::INSUFFICIENT_STORAGE,
+        "cache directory is full",
+        "server_error",
+      )
+    }
+    _ => ApiError::server_error("cache directory not writable"),
+  })
+}
+
+/// Filename prefix of input images written to `cache_dir` for the duration
+/// of a request.
+pub const INPUT_PREFIX: &str = "sd_input_";
+
+/// Decodes a base64 input image and returns it along with the file extension
+/// matching its format. Only PNG and JPEG are accepted.
+pub fn decode_image(
+  encoded: &str,
+) -> Result<(Vec<u8>, &'static str), ApiError> {
+  let encoded = match encoded.split_once("base64,") {
+    Some((_, data)) => data,
+    None => encoded,
+  };
+  let data = base64::Engine::decode(
+    &base64::engine::general_purpose::STANDARD,
+    encoded.trim(),
+  )
+  .map_err(|_| ApiError::bad_request("image must be valid base64"))?;
+  match image_extension(&data) {
+    Some(extension) => Ok((data, extension)),
+    None => Err(ApiError::bad_request("image must be a PNG or JPEG")),
+  }
+}
+
+/// Bytes `image_extension` needs at most.
+pub const IMAGE_MAGIC_LEN: usize = 8;
+
+/// Detects the image format from its magic bytes.
+pub fn image_extension(data: &[u8]) -> Option<&'static str> {
+  if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+    Some("png")
+  } else if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+    Some("jpg")
+  } else {
+    None
+  }
+}
+
+/// Periodically removes images kept for URL serving once they are older
+/// than `image_ttl`.
+pub async fn cleanup_expired_images(context: Context) {
+  let mut interval = tokio::time::interval(Duration::from_secs(60));
+  loop {
+    interval.tick().await;
+    remove_old_files(&context.cache_dir, &[OUTPUT_PREFIX], context.image_ttl)
+      .await;
+  }
+}
+
+/// Removes the files in `dir` whose name starts with one of `prefixes` and
+/// that were last modified more than `max_age` ago.
+pub async fn remove_old_files(dir: &str, prefixes: &[&str], max_age: Duration) {
+  let mut entries = match tokio::fs::read_dir(dir).await {
+    Ok(entries) => entries,
+    Err(e) => {
+      tracing::error!(error = %e, "failed to read cache directory");
+      return;
+    }
+  };
+  while let Ok(Some(entry)) = entries.next_entry().await {
+    let name = entry.file_name();
+    let name = name.to_string_lossy();
+    if !prefixes.iter().any(|prefix| name.starts_with(prefix)) {
+      continue;
+    }
+    let expired = entry
+      .metadata()
+      .await
+      .and_then(|metadata| metadata.modified())
+      .ok()
+      .and_then(|modified| modified.elapsed().ok())
+      .is_some_and(|age| age > max_age);
+    if expired {
+      tracing::info!(path = ?entry.path(), "removing old file");
+      let _ = tokio::fs::remove_file(entry.path()).await;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::models_dir;
+  use std::time::SystemTime;
+
+  fn encode(format: image::ImageFormat) -> Vec<u8> {
+    let mut data = std::io::Cursor::new(Vec::new());
+    image::DynamicImage::new_rgb8(8, 8)
+      .write_to(&mut data, format)
+      .unwrap();
+    data.into_inner()
+  }
+
+  #[test]
+  fn images_are_sniffed_by_their_magic_bytes() {
+    let png = encode(image::ImageFormat::Png);
+    assert_eq!(image_extension(&png), Some("png"));
+    assert_eq!(image_extension(&png[..IMAGE_MAGIC_LEN]), Some("png"));
+    assert_eq!(image_extension(&png[..4]), None);
+    let jpeg = encode(image::ImageFormat::Jpeg);
+    assert_eq!(image_extension(&jpeg), Some("jpg"));
+    assert_eq!(image_extension(&jpeg[..IMAGE_MAGIC_LEN]), Some("jpg"));
+
+    assert_eq!(image_extension(&encode(image::ImageFormat::WebP)), None);
+    assert_eq!(image_extension(b"GIF89a\x01\0"), None);
+    assert_eq!(image_extension(b"<svg xmlns=\"\">"), None);
+    assert_eq!(image_extension(b""), None);
+  }
+
+  #[tokio::test]
+  async fn old_leftovers_are_removed() {
+    let dir = models_dir();
+    let hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    let files = [
+      (format!("{}old.png", OUTPUT_PREFIX), true),
+      (format!("{}old.png", INPUT_PREFIX), true),
+      (format!("{}new.png", OUTPUT_PREFIX), false),
+      ("other.png".to_string(), true),
+    ];
+    for (name, old) in &files {
+      let file = std::fs::File::create(dir.join(name)).unwrap();
+      if *old {
+        file.set_modified(hour_ago).unwrap();
+      }
+    }
+
+    remove_old_files(
+      dir.to_str().unwrap(),
+      &[OUTPUT_PREFIX, INPUT_PREFIX],
+      Duration::from_secs(60),
+    )
+    .await;
+    let exists = |name: &String| dir.join(name).exists();
+    assert!(!exists(&files[0].0));
+    assert!(!exists(&files[1].0));
+    assert!(exists(&files[2].0), "a file in use was removed");
+    assert!(exists(&files[3].0), "a file of someone else was removed");
+  }
+}
@@ -0,0 +1,3352 @@
+//! Turning a validated request into binary invocations and their images.
+
+use crate::api::{
+  Background, FormatSpec, GenerationMetadata, ImageData,
+  ImageGenerationRequest, ImageGenerationResponse, ImageInfo,
+  ImageSweepResponse, LoraSpec, OutputFormat, Quality, QualityPreset,
+  ResponseFormat, SweepImage, DEFAULT_REFINER_SWITCH_AT, DEFAULT_STEPS,
+  SAMPLERS,
+};
+use crate::cache::{
+  cache_key, read_cached_result, store_cached_result, RESULT_CACHE_PREFIX,
+};
+use crate::config::{Context, ModelHash};
+use crate::error::{ApiError, ErrorResponse};
+use crate::files::{
+  check_cache_dir, unique_name, TempFile, INPUT_PREFIX, OUTPUT_PREFIX,
+};
+use crate::jobs::{cancelled_error, Cancellation};
+use crate::persistent::{WarmJob, KEEP_ALIVE_FLAG};
+use crate::process::{run_output, ReapedChild};
+use crate::queue::{acquire_device, acquire_generation_slot};
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use std::ops::RangeInclusive;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::process::Command;
+
+/// Runs the binary `body.n` times and builds the response in the requested
+/// `response_format`. The generation can be cancelled by its request ID,
+/// with the request's bearer `token`, until it completes. `on_start` runs
+/// once a generation slot is acquired.
+pub async fn generate_images(
+  base_url: &str,
+  context: &Context,
+  token: &str,
+  body: &ImageGenerationRequest,
+  resolved: &ResolvedRequest,
+  on_start: impl FnOnce(),
+) -> Result<HttpResponse, ApiError> {
+  let cancellation = Cancellation::register(context, token);
+  generate_registered_images(
+    base_url,
+    context,
+    body,
+    resolved,
+    cancellation,
+    on_start,
+  )
+  .await
+}
+
+/// `generate_images` with its cancellation registered beforehand, as jobs
+/// do before they are listed so they can be cancelled while queued.
+pub async fn generate_registered_images(
+  base_url: &str,
+  context: &Context,
+  body: &ImageGenerationRequest,
+  resolved: &ResolvedRequest,
+  mut cancellation: Cancellation,
+  on_start: impl FnOnce(),
+) -> Result<HttpResponse, ApiError> {
+  if body.dry_run {
+    return dry_run(context, body, resolved);
+  }
+  tokio::select! {
+    response = run_images(base_url, context, body, resolved, on_start) =>
+      response,
+    // Dropping the generation kills the binary and removes its files.
+    _ = cancellation.cancelled() => Err(cancelled_error(context)),
+  }
+}
+
+async fn run_images(
+  base_url: &str,
+  context: &Context,
+  body: &ImageGenerationRequest,
+  resolved: &ResolvedRequest,
+  on_start: impl FnOnce(),
+) -> Result<HttpResponse, ApiError> {
+  let _in_flight = context.metrics.in_flight();
+  let _slot = acquire_generation_slot(context).await?;
+  let _device = acquire_device(context, body).await;
+  on_start();
+
+  let started = Instant::now();
+  let timestamp = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap()
+    .as_secs();
+
+  let name = unique_name();
+  let mut data = Vec::with_capacity(body.n as usize);
+  let mut all_cached = true;
+  let mut files = Vec::new();
+  let mut url_files = Vec::new();
+  let mut logs = Vec::new();
+  let base_seed = pick_seed(body);
+  for index in 0..body.n {
+    let output = TempFile::output(
+      context,
+      format!(
+        "{}/{}{}_{}.tmp.png",
+        context.cache_dir, OUTPUT_PREFIX, name, index
+      ),
+    );
+    let seed = batch_seed(base_seed, index);
+    let RenderedImage {
+      mut images,
+      cached,
+      logs: image_logs,
+    } = render_image(context, body, resolved, seed, output.path()).await?;
+    all_cached &= cached;
+    logs.extend(image_logs);
+    match body.response_format {
+      ResponseFormat::B64Json | ResponseFormat::Url => {
+        let variants = output_variants(body);
+        for (variant, (spec, image_data)) in
+          variants.iter().zip(images).enumerate()
+        {
+          let name = match body.formats.is_empty() {
+            true => format!("{}_{}", name, index),
+            false => format!("{}_{}_{}", name, index, variant),
+          };
+          let size = image_info(&image_data)
+            .map(|info| format!("{}x{}", info.width, info.height));
+          let (mut image, url_file) = response_image(
+            base_url,
+            context,
+            body,
+            spec.format,
+            &name,
+            image_data,
+            seed,
+          )
+          .await?;
+          url_files.extend(url_file);
+          image.revised_prompt = Some(resolved.prompt.clone());
+          if !body.formats.is_empty() {
+            image.format = Some(spec.format);
+            image.size = size;
+          }
+          data.push(image);
+        }
+      }
+      ResponseFormat::Zip => {
+        let image_data = images.remove(0);
+        let info = body
+          .include_metadata
+          .then(|| image_info(&image_data))
+          .flatten();
+        files.push((
+          format!("image_{}.{}", index, body.output_format.extension()),
+          image_data,
+        ));
+        data.push(ImageData {
+          b64_json: None,
+          url: None,
+          seed,
+          revised_prompt: Some(resolved.prompt.clone()),
+          format: None,
+          size: None,
+          info,
+        });
+      }
+    }
+  }
+
+  let mut response = HttpResponse::Ok();
+  if context.cache_results {
+    response
+      .insert_header(("X-Cache", if all_cached { "HIT" } else { "MISS" }));
+  }
+  let metadata = generation_metadata(
+    context,
+    body,
+    resolved,
+    data.first().map(|image| image.seed),
+    started.elapsed(),
+    logs,
+  );
+  if let ResponseFormat::Zip = body.response_format {
+    let manifest = serde_json::json!({
+      "created": timestamp,
+      "output_format": body.output_format,
+      "images": files
+        .iter()
+        .zip(&data)
+        .map(|((file, _), image)| {
+          serde_json::json!({ "file": file, "seed": image.seed })
+        })
+        .collect::<Vec<_>>(),
+      "metadata": metadata,
+    });
+    files.push(("metadata.json".to_string(), manifest.to_string().into()));
+    let archive = zip_archive(&files).map_err(|e| {
+      tracing::error!(error = %e, "failed to build zip archive");
+      context.metrics.record_failure("server_error");
+      ApiError::server_error("Failed to build zip archive")
+    })?;
+    return Ok(
+      response
+        .content_type("application/zip")
+        .insert_header((
+          "Content-Disposition",
+          format!("attachment; filename=\"images_{}.zip\"", timestamp),
+        ))
+        .body(archive),
+    );
+  }
+  url_files.into_iter().for_each(TempFile::persist);
+  Ok(response.json(ImageGenerationResponse {
+    created: timestamp,
+    data,
+    output_format: body.output_format,
+    metadata,
+  }))
+}
+
+/// Answers with the arguments the binary would be run with for each image,
+/// without running it. Arguments holding one of the server's tokens, which
+/// could only come from the configured `args`, are redacted.
+fn dry_run(
+  context: &Context,
+  body: &ImageGenerationRequest,
+  resolved: &ResolvedRequest,
+) -> Result<HttpResponse, ApiError> {
+  let name = unique_name();
+  let base_seed = pick_seed(body);
+  let mut commands = Vec::with_capacity(body.n as usize);
+  for index in 0..body.n {
+    let seed = batch_seed(base_seed, index);
+    let output_path = format!(
+      "{}/{}{}_{}.tmp.png",
+      context.cache_dir, OUTPUT_PREFIX, name, index
+    );
+    let cmd = build_command(context, body, resolved, seed, &output_path)?;
+    let args = redact_args(
+      context,
+      cmd.as_std().get_args().map(|arg| arg.to_string_lossy()),
+    );
+    commands.push(serde_json::json!({ "seed": seed, "args": args }));
+  }
+  tracing::info!(images = body.n, "dry run");
+  Ok(HttpResponse::Ok().json(serde_json::json!({
+    "dry_run": true,
+    "binary": resolved.binary,
+    "model": resolved.model,
+    "refiner": resolved.refiner,
+    "prompt": resolved.prompt,
+    "negative_prompt": resolved.negative_prompt,
+    "warnings": resolved.warnings,
+    "commands": commands,
+  })))
+}
+
+/// `args` with those holding one of the server's tokens replaced, to show
+/// them to clients.
+pub fn redact_args(
+  context: &Context,
+  args: impl IntoIterator<Item = impl AsRef<str>>,
+) -> Vec<String> {
+  let secrets = secrets(context);
+  args
+    .into_iter()
+    .map(|arg| {
+      let arg = arg.as_ref();
+      if secrets.iter().any(|secret| arg.contains(secret)) {
+        "[redacted]".to_string()
+      } else {
+        arg.to_string()
+      }
+    })
+    .collect()
+}
+
+/// The server's tokens, to keep out of what is shown to clients.
+fn secrets(context: &Context) -> Vec<&str> {
+  context
+    .tokens
+    .iter()
+    .chain(&context.admin_token)
+    .chain(&context.metrics_token)
+    .map(String::as_str)
+    .filter(|secret| !secret.is_empty())
+    .collect()
+}
+
+/// Most of an image's logs returned with `include_logs`, kept from the end
+/// where errors and timings are.
+const MAX_LOG_BYTES: usize = 16 * 1024;
+
+/// The binary's output as returned with `include_logs`: progress bar
+/// redraws collapsed, tokens redacted, paths cut to their file name, and
+/// cut to its last `MAX_LOG_BYTES`.
+pub fn generation_logs(context: &Context, output: &str) -> String {
+  let secrets = secrets(context);
+  let mut logs = String::new();
+  for line in output.lines() {
+    let Some(line) = line.rsplit('\r').find(|part| !part.trim().is_empty())
+    else {
+      continue;
+    };
+    let mut line = redact_paths(line.trim_end());
+    for secret in &secrets {
+      line = line.replace(secret, "[redacted]");
+    }
+    logs.push_str(&line);
+    logs.push('\n');
+  }
+  if logs.len() <= MAX_LOG_BYTES {
+    return logs;
+  }
+  let mut cut = logs.len() - MAX_LOG_BYTES;
+  cut = logs[cut..]
+    .find('\n')
+    .map_or(logs.len(), |end| cut + end + 1);
+  format!("[truncated]\n{}", &logs[cut..])
+}
+
+/// Replaces absolute paths, such as the models directory, with their file
+/// name.
+fn redact_paths(line: &str) -> String {
+  let is_end = |c: char| c.is_whitespace() || "'\"()[],".contains(c);
+  let mut redacted = String::with_capacity(line.len());
+  let mut rest = line;
+  while let Some(start) = rest.find('/') {
+    let (before, word) = rest.split_at(start);
+    let end = word.find(is_end).unwrap_or(word.len());
+    redacted.push_str(before);
+    // Fractions such as `3/20` or `1.2s/it` are not paths.
+    if before
+      .chars()
+      .next_back()
+      .is_none_or(|c| is_end(c) || c == '=')
+    {
+      redacted.push_str(".../");
+      redacted.push_str(word[..end].rsplit('/').next().unwrap_or_default());
+    } else {
+      redacted.push_str(&word[..end]);
+    }
+    rest = &word[end..];
+  }
+  redacted.push_str(rest);
+  redacted
+}
+
+/// Runs the binary once per seed of `seeds`, each waiting for its own
+/// generation slot. A seed that fails is reported in the response instead
+/// of failing the sweep. Cancellable like `generate_images`.
+pub async fn generate_sweep(
+  base_url: &str,
+  context: &Context,
+  token: &str,
+  body: &ImageGenerationRequest,
+  resolved: &ResolvedRequest,
+  seeds: RangeInclusive<i64>,
+) -> Result<HttpResponse, ApiError> {
+  let mut cancellation = Cancellation::register(context, token);
+  tokio::select! {
+    response = run_sweep(base_url, context, body, resolved, seeds) => response,
+    _ = cancellation.cancelled() => Err(cancelled_error(context)),
+  }
+}
+
+async fn run_sweep(
+  base_url: &str,
+  context: &Context,
+  body: &ImageGenerationRequest,
+  resolved: &ResolvedRequest,
+  seeds: RangeInclusive<i64>,
+) -> Result<HttpResponse, ApiError> {
+  let _in_flight = context.metrics.in_flight();
+  let started = Instant::now();
+  let timestamp = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap()
+    .as_secs();
+
+  let name = unique_name();
+  let mut data = Vec::new();
+  let mut url_files = Vec::new();
+  let mut first_seed = None;
+  for (index, seed) in seeds.enumerate() {
+    let name = format!("{}_{}", name, index);
+    let image = sweep_image(
+      base_url,
+      context,
+      body,
+      resolved,
+      &name,
+      seed,
+      &mut url_files,
+    )
+    .await;
+    match image {
+      Ok(image) => {
+        first_seed = first_seed.or(Some(seed));
+        data.push(SweepImage::Image(image));
+      }
+      Err(e) => {
+        tracing::warn!(seed, error = %e, "sweep seed failed");
+        data.push(SweepImage::Failed {
+          seed,
+          error: ErrorResponse::new(e.message(), e.error_type()),
+        });
+      }
+    }
+  }
+
+  url_files.into_iter().for_each(TempFile::persist);
+  Ok(HttpResponse::Ok().json(ImageSweepResponse {
+    created: timestamp,
+    data,
+    output_format: body.output_format,
+    metadata: generation_metadata(
+      context,
+      body,
+      resolved,
+      first_seed,
+      started.elapsed(),
+      Vec::new(),
+    ),
+  }))
+}
+
+async fn sweep_image(
+  base_url: &str,
+  context: &Context,
+  body: &ImageGenerationRequest,
+  resolved: &ResolvedRequest,
+  name: &str,
+  seed: i64,
+  url_files: &mut Vec<TempFile>,
+) -> Result<ImageData, ApiError> {
+  let _slot = acquire_generation_slot(context).await?;
+  let _device = acquire_device(context, body).await;
+  let output = TempFile::output(
+    context,
+    format!("{}/{}{}.tmp.png", context.cache_dir, OUTPUT_PREFIX, name),
+  );
+  let image_data = render_image(context, body, resolved, seed, output.path())
+    .await?
+    .images
+    .remove(0);
+  let format = body.output_format;
+  let (mut image, url_file) =
+    response_image(base_url, context, body, format, name, image_data, seed)
+      .await?;
+  url_files.extend(url_file);
+  image.revised_prompt = Some(resolved.prompt.clone());
+  Ok(image)
+}
+
+/// The `data` entry of an image encoded as `format`, answered as `b64_json`
+/// or `url`, without its `revised_prompt`. URL outputs are uploaded to
+/// `output_store`, or kept in `cache_dir` under `name` as the returned file,
+/// to be persisted once the response is ready and removed when the
+/// generation fails or is cancelled before.
+async fn response_image(
+  base_url: &str,
+  context: &Context,
+  body: &ImageGenerationRequest,
+  format: OutputFormat,
+  name: &str,
+  image_data: Vec<u8>,
+  seed: i64,
+) -> Result<(ImageData, Option<TempFile>), ApiError> {
+  let info = body
+    .include_metadata
+    .then(|| image_info(&image_data))
+    .flatten();
+  if let ResponseFormat::B64Json = body.response_format {
+    let b64_json = base64::Engine::encode(
+      &base64::engine::general_purpose::STANDARD,
+      &image_data,
+    );
+    let image = ImageData {
+      b64_json: Some(b64_json),
+      url: None,
+      seed,
+      revised_prompt: None,
+      format: None,
+      size: None,
+      info,
+    };
+    return Ok((image, None));
+  }
+  let filename = format!("{}{}.{}", OUTPUT_PREFIX, name, format.extension());
+  let mut url_file = None;
+  let url = match &context.output_store {
+    Some((store, public_url)) => {
+      if let Err(e) = store.upload(&filename, image_data).await {
+        tracing::error!(error = %e, "failed to upload output image");
+        context.metrics.record_failure("server_error");
+        return Err(ApiError::server_error("Failed to upload output image"));
+      }
+      format!("{}/{}", public_url, filename)
+    }
+    None => {
+      let file = TempFile::new(format!("{}/{}", context.cache_dir, filename));
+      if let Err(e) = tokio::fs::write(file.path(), &image_data).await {
+        tracing::error!(error = %e, "failed to write output image");
+        context.metrics.record_failure("server_error");
+        return Err(ApiError::server_error("Failed to write output image"));
+      }
+      url_file = Some(file);
+      format!("{}/images/{}", base_url, filename)
+    }
+  };
+  let image = ImageData {
+    b64_json: None,
+    url: Some(url),
+    seed,
+    revised_prompt: None,
+    format: None,
+    size: None,
+    info,
+  };
+  Ok((image, url_file))
+}
+
+/// Packs `(name, data)` files into a ZIP archive. Images are already
+/// compressed, so they are stored as is.
+fn zip_archive(files: &[(String, Vec<u8>)]) -> zip::result::ZipResult<Vec<u8>> {
+  use std::io::Write;
+  let mut archive = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+  let options = zip::write::SimpleFileOptions::default()
+    .compression_method(zip::CompressionMethod::Stored);
+  for (name, data) in files {
+    archive.start_file(name.as_str(), options)?;
+    archive.write_all(data)?;
+  }
+  Ok(archive.finish()?.into_inner())
+}
+
+/// Same as `generate_images` for a single image, answered with the image
+/// itself rather than JSON. Its seed is in the `X-Seed` header.
+pub async fn generate_raw_image(
+  context: &Context,
+  token: &str,
+  body: &ImageGenerationRequest,
+  resolved: &ResolvedRequest,
+) -> Result<HttpResponse, ApiError> {
+  if body.dry_run {
+    return dry_run(context, body, resolved);
+  }
+  let mut cancellation = Cancellation::register(context, token);
+  tokio::select! {
+    response = run_raw_image(context, body, resolved) => response,
+    _ = cancellation.cancelled() => Err(cancelled_error(context)),
+  }
+}
+
+async fn run_raw_image(
+  context: &Context,
+  body: &ImageGenerationRequest,
+  resolved: &ResolvedRequest,
+) -> Result<HttpResponse, ApiError> {
+  let _in_flight = context.metrics.in_flight();
+  let _slot = acquire_generation_slot(context).await?;
+  let _device = acquire_device(context, body).await;
+
+  let output = TempFile::output(
+    context,
+    format!(
+      "{}/{}{}_raw.tmp.png",
+      context.cache_dir,
+      OUTPUT_PREFIX,
+      unique_name()
+    ),
+  );
+  let seed = pick_seed(body);
+  let RenderedImage {
+    mut images, cached, ..
+  } = render_image(context, body, resolved, seed, output.path()).await?;
+  let image_data = images.remove(0);
+
+  let mut response = HttpResponse::Ok();
+  if context.cache_results {
+    response.insert_header(("X-Cache", if cached { "HIT" } else { "MISS" }));
+  }
+  Ok(
+    response
+      .content_type(body.output_format.image_format().to_mime_type())
+      .insert_header(("X-Seed", seed.to_string()))
+      .body(image_data),
+  )
+}
+
+struct RenderedImage {
+  /// One per entry of `output_variants`.
+  images: Vec<Vec<u8>>,
+  cached: bool,
+  /// The binary's output, with `include_logs`. Empty for cached images.
+  logs: Option<String>,
+}
+
+/// Produces one image in each of the requested formats, from the result
+/// cache when possible.
+async fn render_image(
+  context: &Context,
+  body: &ImageGenerationRequest,
+  resolved: &ResolvedRequest,
+  seed: i64,
+  output_path: &str,
+) -> Result<RenderedImage, ApiError> {
+  // Only explicit seeds can ever be requested again.
+  let cache_path = (context.cache_results && body.seed.is_some()).then(|| {
+    format!(
+      "{}/{}{}.png",
+      context.cache_dir,
+      RESULT_CACHE_PREFIX,
+      cache_key(context, body, resolved, seed)
+    )
+  });
+  let cached = match &cache_path {
+    Some(cache_path) => read_cached_result(cache_path).await,
+    None => None,
+  };
+  let is_cached = cached.is_some();
+  let (image_data, output) = match cached {
+    Some(image_data) => {
+      tracing::info!("result cache hit");
+      (image_data, String::new())
+    }
+    None => {
+      let (image_data, output) =
+        run_generation(context, body, resolved, seed, output_path).await?;
+      if let Some(cache_path) = &cache_path {
+        store_cached_result(context, cache_path, &image_data).await;
+      }
+      (image_data, output)
+    }
+  };
+  let logs = body.include_logs.then(|| generation_logs(context, &output));
+  let image_data = remove_background(context, body, image_data).await?;
+  let mut images = Vec::new();
+  for spec in output_variants(body) {
+    let encoded =
+      encode_image(image_data.clone(), body, &spec).map_err(|e| {
+        tracing::error!(error = %e, "failed to encode output image");
+        context.metrics.record_failure("server_error");
+        ApiError::server_error("Failed to encode output image")
+      })?;
+    // Only PNG has room for the parameters.
+    if !body.embed_metadata || spec.format != OutputFormat::Png {
+      images.push(encoded);
+      continue;
+    }
+    let parameters = generation_parameters(context, body, resolved, seed);
+    images.push(embed_parameters(encoded, &parameters).map_err(|e| {
+      tracing::error!(error = %e, "failed to embed generation parameters");
+      context.metrics.record_failure("server_error");
+      ApiError::server_error("Failed to embed generation parameters")
+    })?);
+  }
+  Ok(RenderedImage {
+    images,
+    cached: is_cached,
+    logs,
+  })
+}
+
+/// The encodings of every image: the entries of `formats`, or else the
+/// `output_format` and `quality` of the request.
+fn output_variants(body: &ImageGenerationRequest) -> Vec<FormatSpec> {
+  if !body.formats.is_empty() {
+    return body.formats.clone();
+  }
+  vec![FormatSpec {
+    format: body.output_format,
+    max_dimension: None,
+    quality: body.quality.and_then(Quality::encoder),
+  }]
+}
+
+/// Runs `background_remover` on an image when the request asks for a
+/// transparent background, which the binary cannot render itself.
+pub async fn remove_background(
+  context: &Context,
+  body: &ImageGenerationRequest,
+  image_data: Vec<u8>,
+) -> Result<Vec<u8>, ApiError> {
+  let (Background::Transparent, Some(remover)) =
+    (body.background, &context.background_remover)
+  else {
+    return Ok(image_data);
+  };
+  let name = unique_name();
+  let input = TempFile::new(format!(
+    "{}/{}{}_opaque.png",
+    context.cache_dir, OUTPUT_PREFIX, name
+  ));
+  let output = TempFile::output(
+    context,
+    format!(
+      "{}/{}{}_transparent.png",
+      context.cache_dir, OUTPUT_PREFIX, name
+    ),
+  );
+  let failed = |message: String| {
+    tracing::error!(error = %message, "background removal failed");
+    context.metrics.record_failure("server_error");
+    ApiError::server_error(format!("Background removal failed: {}", message))
+  };
+  tokio::fs::write(input.path(), &image_data)
+    .await
+    .map_err(|e| failed(e.to_string()))?;
+
+  let mut cmd = Command::new(&remover[0]);
+  cmd.args(&remover[1..]).arg(input.path()).arg(output.path());
+  let started = Instant::now();
+  let run = run_output(&mut cmd, context.timeout)
+    .await
+    .map_err(|e| failed(e.to_string()))?;
+  if !run.status.success() {
+    let stderr = String::from_utf8_lossy(&run.stderr);
+    return Err(failed(match stderr.trim() {
+      "" => run.status.to_string(),
+      stderr => stderr.to_string(),
+    }));
+  }
+  let image_data = tokio::fs::read(output.path())
+    .await
+    .map_err(|e| failed(e.to_string()))?;
+  if image_info(&image_data).is_none() {
+    return Err(failed(INVALID_OUTPUT.to_string()));
+  }
+  tracing::info!(
+    duration_ms = started.elapsed().as_millis() as u64,
+    "background removed"
+  );
+  Ok(image_data)
+}
+
+/// Error of a generation whose output is not a readable image.
+pub const INVALID_OUTPUT: &str = "generation produced invalid output";
+
+/// Dimensions, size and color type of an encoded image, read from its
+/// header only.
+pub fn image_info(image_data: &[u8]) -> Option<ImageInfo> {
+  use image::ImageDecoder;
+  let decoder = image::ImageReader::new(std::io::Cursor::new(image_data))
+    .with_guessed_format()
+    .ok()?
+    .into_decoder()
+    .ok()?;
+  let (width, height) = decoder.dimensions();
+  Some(ImageInfo {
+    width,
+    height,
+    bytes: image_data.len(),
+    color_type: format!("{:?}", decoder.color_type()).to_lowercase(),
+  })
+}
+
+/// Random seeds are picked here rather than by the binary so the response
+/// can report them, and stay below 2^31 for clients storing them as 32-bit
+/// integers. This is the seed of the first image of a batch.
+pub fn pick_seed(body: &ImageGenerationRequest) -> i64 {
+  let seed = body
+    .seed
+    .unwrap_or_else(|| rand::random_range(0..i32::MAX as i64));
+  tracing::info!(seed, "seed");
+  seed
+}
+
+/// Image `index` of a batch uses the base seed plus `index`, so the whole
+/// batch is reproducible from its first seed.
+pub fn batch_seed(base_seed: i64, index: u32) -> i64 {
+  // Wraps past `i64::MAX` back to 0, as the binary treats negative seeds as
+  // random.
+  base_seed.wrapping_add(index as i64) & i64::MAX
+}
+
+/// Effective parameters of a finished generation, only returned when the
+/// request sets `include_metadata`. `seed` is that of its first image.
+pub fn generation_metadata(
+  context: &Context,
+  body: &ImageGenerationRequest,
+  resolved: &ResolvedRequest,
+  seed: Option<i64>,
+  duration: Duration,
+  logs: Vec<String>,
+) -> Option<GenerationMetadata> {
+  let included = body.include_metadata || body.include_logs;
+  included.then(|| GenerationMetadata {
+    duration_ms: duration.as_millis() as u64,
+    seed,
+    model: body.model.clone(),
+    model_file: file_name(&resolved.model).to_string(),
+    model_sha256: resolved.model_sha256.clone(),
+    steps: effective_steps(context, body),
+    cfg_scale: effective_cfg_scale(context, body),
+    sampler: effective_sampler(body).map(str::to_string),
+    vae: body.vae.clone(),
+    clip_skip: body.clip_skip,
+    strength: resolved.strength,
+    refiner_model: body.refiner_model.clone(),
+    refiner_switch_at: body
+      .refiner_model
+      .as_ref()
+      .map(|_| effective_refiner_switch_at(body)),
+    background: body.background,
+    backend: body
+      .backend
+      .clone()
+      .or_else(|| context.default_backend.clone()),
+    prompt_truncated: estimate_clip_tokens(&resolved.prompt) > CLIP_MAX_TOKENS,
+    warnings: resolved.warnings.clone(),
+    logs,
+  })
+}
+
+/// Tokens of a CLIP prompt, leaving out its start and end tokens.
+const CLIP_MAX_TOKENS: usize = 75;
+
+/// Rough CLIP token count: a token per punctuation mark and per started
+/// group of four letters or digits, about what its BPE vocabulary gives for
+/// English words. LoRA tags are left out, as the binary removes them from
+/// the prompt.
+fn estimate_clip_tokens(prompt: &str) -> usize {
+  let mut text = String::new();
+  let mut rest = prompt;
+  while let Some(start) = rest.find("<lora:") {
+    text.push_str(&rest[..start]);
+    rest = rest[start..].split_once('>').map_or("", |(_, rest)| rest);
+  }
+  text.push_str(rest);
+  let mut tokens = 0;
+  let mut run = 0;
+  for c in text.chars() {
+    if c.is_alphanumeric() {
+      if run % 4 == 0 {
+        tokens += 1;
+      }
+      run += 1;
+    } else {
+      run = 0;
+      if !c.is_whitespace() {
+        tokens += 1;
+      }
+    }
+  }
+  tokens
+}
+
+/// Checks the attention syntax of a prompt, `(text)`, `[text]` and
+/// `(text:1.2)`, which the binary fails on deep in its parser when
+/// malformed. Brackets must balance unless escaped with `\` or part of an
+/// emoticon such as `:)`, and a weight made of digits after the last colon
+/// of a group must parse, as `1.2.3` does not. Other text after a colon, as
+/// in `(note: blue)` or `(ratio 3:2 landscape)`, is left alone.
+fn check_prompt_weights(prompt: &str) -> Result<(), String> {
+  // Open brackets with their position and the position of the group's
+  // last colon.
+  let mut open: Vec<(char, usize, Option<usize>)> = Vec::new();
+  let chars: Vec<char> = prompt.chars().collect();
+  let mut escaped = false;
+  for (position, &c) in chars.iter().enumerate() {
+    if escaped {
+      escaped = false;
+      continue;
+    }
+    // Eyes, with an optional nose, before a bracket.
+    let emoticon = matches!(
+      chars[..position],
+      [.., ':' | ';' | '=', '-'] | [.., ':' | ';' | '=']
+    );
+    match c {
+      '\\' => escaped = true,
+      '(' | ')' | '[' | ']' if emoticon => {}
+      '(' | '[' => open.push((c, position, None)),
+      ':' => {
+        if let Some((_, _, colon)) = open.last_mut() {
+          *colon = Some(position);
+        }
+      }
+      ')' | ']' => {
+        let expected = if c == ')' { '(' } else { '[' };
+        match open.pop() {
+          Some((bracket, _, colon)) if bracket == expected => {
+            let Some(colon) = colon else { continue };
+            let weight: String = chars[colon + 1..position].iter().collect();
+            let weight = weight.trim();
+            let numeric = weight.contains(|c: char| c.is_ascii_digit())
+              && weight
+                .chars()
+                .all(|c| c.is_ascii_digit() || "+-.".contains(c));
+            if numeric && weight.parse::<f32>().is_err() {
+              return Err(format!(
+                "has an invalid weight '{}' at position {}",
+                weight,
+                colon + 1
+              ));
+            }
+          }
+          Some((bracket, start, _)) => {
+            return Err(format!(
+              "closes '{}' at position {} with '{}' at position {}",
+              bracket, start, c, position
+            ));
+          }
+          None => {
+            return Err(format!(
+              "has an unmatched '{}' at position {}",
+              c, position
+            ));
+          }
+        }
+      }
+      _ => {}
+    }
+  }
+  match open.first() {
+    Some((bracket, start, _)) => Err(format!(
+      "has an unclosed '{}' at position {}",
+      bracket, start
+    )),
+    None => Ok(()),
+  }
+}
+
+/// A `quality` preset overrides the requested `steps`.
+pub fn effective_steps(
+  context: &Context,
+  body: &ImageGenerationRequest,
+) -> u32 {
+  match body.quality {
+    Some(Quality::Preset(QualityPreset::Standard)) => DEFAULT_STEPS,
+    Some(Quality::Preset(QualityPreset::Hd)) => context.hd_steps,
+    _ => body.steps,
+  }
+}
+
+/// `force_scale` overrides the requested `cfg_scale`.
+pub fn effective_cfg_scale(
+  context: &Context,
+  body: &ImageGenerationRequest,
+) -> f32 {
+  context.force_scale.map_or(body.cfg_scale, |s| s as f32)
+}
+
+/// Share of the denoising left to the refiner is `1 - switch_at`.
+pub fn effective_refiner_switch_at(body: &ImageGenerationRequest) -> f32 {
+  body.refiner_switch_at.unwrap_or(DEFAULT_REFINER_SWITCH_AT)
+}
+
+/// Deterministic requests pin the sampler so a new binary default cannot
+/// change their images.
+pub fn effective_sampler(body: &ImageGenerationRequest) -> Option<&str> {
+  match &body.sampler {
+    Some(sampler) => Some(sampler),
+    None if body.deterministic => Some(DETERMINISTIC_SAMPLER),
+    None => None,
+  }
+}
+
+/// Sampler of deterministic requests that set none.
+const DETERMINISTIC_SAMPLER: &str = "euler_a";
+
+/// Extracts `step/steps` from a binary progress line such as
+/// `  |=====>     | 5/20 - 1.23s/it`.
+pub fn parse_progress(line: &[u8]) -> Option<(u32, u32)> {
+  let line = String::from_utf8_lossy(line);
+  let (_, rest) = line.rsplit_once('|')?;
+  let (step, rest) = rest.trim_start().split_once('/')?;
+  let steps: String = rest.chars().take_while(|c| c.is_ascii_digit()).collect();
+  Some((step.parse().ok()?, steps.parse().ok()?))
+}
+
+/// Characters of a prompt kept in logged command lines.
+const LOGGED_PROMPT_CHARS: usize = 80;
+
+/// The command as a shell line, to reproduce a generation by hand. Prompts
+/// are truncated to `LOGGED_PROMPT_CHARS`, and the command carries no
+/// tokens; only log it at debug level as it holds server paths.
+pub fn command_line(cmd: &Command) -> String {
+  let cmd = cmd.as_std();
+  let mut line = shell_quote(&cmd.get_program().to_string_lossy());
+  let mut prompt_next = false;
+  for arg in cmd.get_args() {
+    let arg = arg.to_string_lossy();
+    let arg = if prompt_next && arg.chars().count() > LOGGED_PROMPT_CHARS {
+      let kept: String = arg.chars().take(LOGGED_PROMPT_CHARS).collect();
+      format!("{}...", kept).into()
+    } else {
+      arg
+    };
+    prompt_next = arg == "-p" || arg == "-n";
+    line.push(' ');
+    line.push_str(&shell_quote(&arg));
+  }
+  line
+}
+
+fn shell_quote(arg: &str) -> String {
+  let plain = !arg.is_empty()
+    && arg
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+".contains(c));
+  if plain {
+    arg.to_string()
+  } else {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+  }
+}
+
+/// Assembles the binary invocation for a single image. The request must
+/// have passed `validate_request`.
+pub fn build_command(
+  context: &Context,
+  body: &ImageGenerationRequest,
+  resolved: &ResolvedRequest,
+  seed: i64,
+  output_path: &str,
+) -> Result<Command, ApiError> {
+  let (width, height) = parse_size(&body.size).ok_or_else(|| {
+    ApiError::bad_request(format!("invalid size '{}'", body.size))
+  })?;
+
+  let mut cmd = Command::new(&resolved.binary);
+  if let Some(args) = &context.args {
+    for arg in args {
+      cmd.arg(arg);
+    }
+  }
+
+  if context.diffusion {
+    cmd.arg("--diffusion-model").arg(&resolved.model);
+  } else {
+    cmd.arg("-m").arg(&resolved.model);
+  }
+
+  // Every user-controlled value is passed as its own argument right after
+  // the flag it belongs to, which the binary always consumes as the value
+  // even when it starts with a dash.
+  match &resolved.prompt_file {
+    Some(prompt_file) => cmd.arg(PROMPT_FILE_FLAG).arg(prompt_file.path()),
+    None => cmd.arg("-p").arg(&resolved.prompt),
+  };
+  cmd.arg("-o").arg(output_path);
+  cmd
+    .arg("--steps")
+    .arg(effective_steps(context, body).to_string());
+
+  cmd
+    .arg("--cfg-scale")
+    .arg(effective_cfg_scale(context, body).to_string());
+
+  cmd.arg("--seed").arg(seed.to_string());
+
+  if let Some(device) = body.device {
+    // Device indices are the driver's enumeration order, as used by these
+    // variables, so the binary only sees the selected GPU.
+    cmd.env("CUDA_VISIBLE_DEVICES", device.to_string());
+    cmd.env("GGML_VK_VISIBLE_DEVICES", device.to_string());
+  }
+
+  let threads = if body.deterministic {
+    // Multithreaded reductions sum in a varying order.
+    Some(1)
+  } else {
+    body.threads.or(context.threads)
+  };
+  if let Some(threads) = threads {
+    cmd.arg("--threads").arg(threads.to_string());
+  }
+
+  match (&resolved.negative_prompt_file, &resolved.negative_prompt) {
+    (Some(file), _) => {
+      cmd.arg(NEGATIVE_PROMPT_FILE_FLAG).arg(file.path());
+    }
+    (None, Some(neg_prompt)) => {
+      cmd.arg("-n").arg(neg_prompt);
+    }
+    (None, None) => {}
+  }
+
+  if let Some(sampler) = effective_sampler(body) {
+    cmd.arg("--sampling-method").arg(sampler);
+  }
+
+  if let Some(clip_skip) = body.clip_skip {
+    cmd.arg("--clip-skip").arg(clip_skip.to_string());
+  }
+
+  if let Some(strength) = resolved.strength {
+    cmd.arg("--strength").arg(strength.to_string());
+  }
+
+  cmd.arg("-W").arg(width.to_string());
+  cmd.arg("-H").arg(height.to_string());
+
+  for (enabled, flag) in [
+    (body.vae_tiling, "--vae-tiling"),
+    (body.diffusion_fa, "--diffusion-fa"),
+    (body.vae_on_cpu, "--vae-on-cpu"),
+    (body.tiling, TILING_FLAG),
+  ] {
+    if enabled {
+      cmd.arg(flag);
+    }
+  }
+
+  if !body.loras.is_empty() {
+    if let Some(loras_dir) = &context.loras_dir {
+      cmd.arg("--lora-model-dir").arg(loras_dir);
+    }
+  }
+
+  for arg in &resolved.extra_args {
+    cmd.arg(arg);
+  }
+
+  Ok(cmd)
+}
+
+/// Generates a single image and returns the binary's PNG output, along with
+/// what it printed.
+pub async fn run_generation(
+  context: &Context,
+  body: &ImageGenerationRequest,
+  resolved: &ResolvedRequest,
+  seed: i64,
+  output_path: &str,
+) -> Result<(Vec<u8>, String), ApiError> {
+  let cmd = build_command(context, body, resolved, seed, output_path)?;
+  tracing::debug!(command = %command_line(&cmd), "running binary");
+  tracing::info!(
+    model = %model_name(&resolved.model),
+    prompt_len = resolved.prompt.len(),
+    user = ?body.user,
+    "generation started"
+  );
+  let generated = run_binary(context, cmd, output_path).await?;
+  context
+    .warm_models
+    .lock()
+    .unwrap()
+    .insert(model_name(&resolved.model).to_string());
+  let Some(refiner) = &resolved.refiner else {
+    return Ok(generated);
+  };
+  // Rounded so that a switch at 0.8 passes 0.2 rather than 0.19999999.
+  let strength =
+    ((1.0 - effective_refiner_switch_at(body)) * 1000.0).round() / 1000.0;
+  if strength <= 0.0 {
+    return Ok(generated);
+  }
+
+  // The refiner denoises the end of the schedule again from the base
+  // image, which stable-diffusion.cpp has no two-model flags for.
+  let (image_data, base_output) = generated;
+  let input = TempFile::new(format!(
+    "{}/{}{}_base.png",
+    context.cache_dir,
+    INPUT_PREFIX,
+    unique_name()
+  ));
+  if let Err(e) = tokio::fs::write(input.path(), &image_data).await {
+    tracing::error!(error = %e, "failed to write base image");
+    context.metrics.record_failure("server_error");
+    return Err(ApiError::server_error(format!(
+      "Failed to write base image: {}",
+      e
+    )));
+  }
+  let mut extra_args = resolved.extra_args.clone();
+  extra_args.push("--init-img".to_string());
+  extra_args.push(input.path().to_string());
+  let refining = ResolvedRequest {
+    model: refiner.clone(),
+    model_sha256: None,
+    prompt: resolved.prompt.clone(),
+    negative_prompt: resolved.negative_prompt.clone(),
+    extra_args,
+    strength: Some(strength),
+    warnings: Vec::new(),
+    binary: resolved.binary.clone(),
+    refiner: None,
+    prompt_file: resolved.prompt_file.clone(),
+    negative_prompt_file: resolved.negative_prompt_file.clone(),
+  };
+  let cmd = build_command(context, body, &refining, seed, output_path)?;
+  tracing::debug!(command = %command_line(&cmd), "running binary");
+  tracing::info!(refiner = %model_name(refiner), strength, "refiner started");
+  let (image_data, refiner_output) =
+    run_binary(context, cmd, output_path).await?;
+  context
+    .warm_models
+    .lock()
+    .unwrap()
+    .insert(model_name(refiner).to_string());
+  Ok((image_data, format!("{}{}", base_output, refiner_output)))
+}
+
+/// Runs the binary, subject to the configured timeout, and reads the image
+/// it wrote to `output_path`, returned with the binary's stdout and stderr.
+/// Failures that look transient are retried up to `retries` times.
+pub async fn run_binary(
+  context: &Context,
+  cmd: Command,
+  output_path: &str,
+) -> Result<(Vec<u8>, String), ApiError> {
+  check_cache_dir(context).await?;
+  context.breaker.admit()?;
+  let result = spawn_binary(context, cmd, output_path).await;
+  // A request the binary cannot take says nothing about its health.
+  if !result
+    .as_ref()
+    .is_err_and(|e| e.status_code().is_client_error())
+  {
+    context.breaker.record(result.is_ok());
+  }
+  result
+}
+
+async fn spawn_binary(
+  context: &Context,
+  mut cmd: Command,
+  output_path: &str,
+) -> Result<(Vec<u8>, String), ApiError> {
+  cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+  let warm = warm_job(context, &cmd).await;
+  let mut attempt = 0;
+  loop {
+    let started = Instant::now();
+    let run = async {
+      match &warm {
+        Some(job) => context.warm_processes.run(job).await,
+        None => ReapedChild::spawn(&mut cmd)?.wait_with_output().await,
+      }
+    };
+    let result = match context.timeout {
+      Some(timeout) => match tokio::time::timeout(timeout, run).await {
+        Ok(result) => result,
+        // Dropping the child or warm process kills and reaps it.
+        Err(_) => return Err(timed_out_error(context, timeout)),
+      },
+      None => run.await,
+    };
+
+    if let Ok(output) = &result {
+      tracing::info!(
+        duration_ms = started.elapsed().as_millis() as u64,
+        exit_status = %output.status,
+        "generation finished"
+      );
+      // Holds the prompt and server paths, as does the command line.
+      tracing::debug!(
+        stdout = %String::from_utf8_lossy(&output.stdout),
+        "binary output"
+      );
+    }
+
+    return match result {
+      Ok(output) => {
+        if output.status.success() {
+          match tokio::fs::read(output_path).await {
+            // An interrupted write can leave an empty or truncated file
+            // behind a successful exit.
+            Ok(image_data) if image_info(&image_data).is_none() => {
+              tracing::error!(
+                bytes = image_data.len(),
+                "binary wrote an invalid image"
+              );
+              context.metrics.record_failure("server_error");
+              Err(ApiError::server_error(INVALID_OUTPUT))
+            }
+            Ok(image_data) => {
+              context.metrics.record_generation(started.elapsed());
+              let output = format!(
+                "{}{}",
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+              );
+              Ok((image_data, output))
+            }
+            Err(e) => {
+              tracing::error!(error = %e, "failed to read output image");
+              context.metrics.record_failure("server_error");
+              Err(ApiError::server_error(format!(
+                "Failed to read output image: {}",
+                e
+              )))
+            }
+          }
+        } else {
+          let stderr = String::from_utf8_lossy(&output.stderr);
+          if attempt < context.retries && is_transient_failure(&stderr) {
+            attempt += 1;
+            let delay = RETRY_BACKOFF * attempt;
+            tracing::warn!(
+              attempt,
+              retries = context.retries,
+              retry_in = ?delay,
+              stderr = %stderr,
+              "transient generation failure, retrying"
+            );
+            tokio::time::sleep(delay).await;
+            continue;
+          }
+          tracing::error!(stderr = %stderr, "generation failed");
+          context.metrics.record_failure("server_error");
+          Err(ApiError::server_error(format!(
+            "Image generation failed: {}",
+            stderr
+          )))
+        }
+      }
+      Err(e) if e.kind() == std::io::ErrorKind::ArgumentListTooLong => {
+        tracing::error!(error = %e, "command line too long");
+        Err(ApiError::bad_request(too_long_message(&cmd)))
+      }
+      Err(e) => {
+        tracing::error!(error = %e, "failed to execute sd command");
+        context.metrics.record_failure("server_error");
+        Err(ApiError::server_error(format!(
+          "Failed to execute sd command: {}",
+          e
+        )))
+      }
+    };
+  }
+}
+
+/// Records a generation that ran past `timeout`, whose binary the caller
+/// kills. The circuit breaker counts it as a failure once the error reaches
+/// `run_binary`, or the caller records it.
+pub fn timed_out_error(context: &Context, timeout: Duration) -> ApiError {
+  tracing::error!(timeout = ?timeout, "generation timed out");
+  context.metrics.record_failure("timeout");
+  ApiError::new(
+    StatusCode::GATEWAY_TIMEOUT,
+    format!(
+      "Image generation timed out after {} seconds",
+      timeout.as_secs()
+    ),
+    "timeout",
+  )
+}
+
+/// The job of `cmd` for a warm process, with `keep_alive` set and a binary
+/// that supports it. Only commands loading a model as `build_command`
+/// does qualify, and not those selecting a device through the environment,
+/// which a warm process is started with.
+async fn warm_job(context: &Context, cmd: &Command) -> Option<WarmJob> {
+  if !context.keep_alive {
+    return None;
+  }
+  let cmd = cmd.as_std();
+  if cmd.get_envs().next().is_some() {
+    return None;
+  }
+  let mut command = vec![cmd.get_program().to_str()?.to_string()];
+  let mut args = cmd
+    .get_args()
+    .map(|arg| arg.to_str().map(str::to_string))
+    .collect::<Option<Vec<_>>>()?;
+  let prefix = context.args.as_ref().map_or(0, Vec::len);
+  if args.len() < prefix + 2
+    || context
+      .args
+      .as_ref()
+      .is_some_and(|a| args[..prefix] != a[..])
+    || !["-m", "--diffusion-model"].contains(&args[prefix].as_str())
+  {
+    return None;
+  }
+  let job_args = args.split_off(prefix + 2);
+  command.extend(args);
+  if !binary_supports(context, &command[0], KEEP_ALIVE_FLAG).await {
+    return None;
+  }
+  Some(WarmJob {
+    command,
+    args: job_args,
+  })
+}
+
+/// Why the command line of `cmd` is too long: prompts left inline because
+/// the binary lacks the flag to read them from a file, or else the rest of
+/// the request.
+fn too_long_message(cmd: &Command) -> String {
+  let inline = |flag: &str| cmd.as_std().get_args().any(|arg| arg == flag);
+  let missing = [("-p", PROMPT_FILE_FLAG), ("-n", NEGATIVE_PROMPT_FILE_FLAG)]
+    .into_iter()
+    .filter(|(flag, _)| inline(flag))
+    .map(|(_, file_flag)| file_flag)
+    .collect::<Vec<_>>();
+  match missing.is_empty() {
+    true => "the command line is too long even with the prompts passed in \
+             files, shorten extra_args"
+      .to_string(),
+    false => format!(
+      "prompts are too long for the command line and the binary does not \
+       support {}, shorten them or update the server's binary",
+      missing.join(" or ")
+    ),
+  }
+}
+
+/// Delay before the first retry of a transient failure, growing linearly
+/// with each further attempt.
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Lowercase stderr fragments of failures that may succeed on a second run,
+/// such as a GPU allocation racing another process. Anything else, like a
+/// missing or corrupt model, fails the same way every time.
+const TRANSIENT_FAILURES: &[&str] = &[
+  "out of memory",
+  "cudamalloc failed",
+  "failed to allocate",
+  "erroroutofdevicememory",
+  "errordevicelost",
+  "device busy",
+  "resource temporarily unavailable",
+];
+
+fn is_transient_failure(stderr: &str) -> bool {
+  let stderr = stderr.to_lowercase();
+  TRANSIENT_FAILURES
+    .iter()
+    .any(|pattern| stderr.contains(pattern))
+}
+
+/// Transcodes the binary's output to the requested `output_format`, depth
+/// and channels.
+pub fn encode_output(
+  image_data: Vec<u8>,
+  body: &ImageGenerationRequest,
+) -> Result<Vec<u8>, image::ImageError> {
+  encode_image(image_data, body, &output_variants(body)[0])
+}
+
+/// Transcodes the binary's output to the format, quality and size of
+/// `spec`, in the depth and channels of the request. The binary writes PNG
+/// unless `args` configure something else, so the actual format is sniffed
+/// from the data and the image passed through when it already matches.
+fn encode_image(
+  image_data: Vec<u8>,
+  body: &ImageGenerationRequest,
+  spec: &FormatSpec,
+) -> Result<Vec<u8>, image::ImageError> {
+  let format = spec.format;
+  let quality = spec.quality;
+  let alpha = body.alpha || body.background == Background::Transparent;
+  let actual = image::guess_format(&image_data)?;
+  if actual == format.image_format()
+    && quality.is_none()
+    && spec.max_dimension.is_none()
+    && body.bit_depth.is_none()
+    && !alpha
+  {
+    return Ok(image_data);
+  }
+  let mut decoded = image::load_from_memory_with_format(&image_data, actual)?;
+  if let Some(max) = spec.max_dimension {
+    if decoded.width() > max || decoded.height() > max {
+      decoded = decoded.resize(max, max, image::imageops::FilterType::Lanczos3);
+    }
+  }
+  let decoded = convert_color(decoded, body.bit_depth, alpha);
+  let mut encoded = Vec::new();
+  match format {
+    OutputFormat::Png => decoded
+      .write_with_encoder(image::codecs::png::PngEncoder::new(&mut encoded))?,
+    OutputFormat::Jpeg => {
+      // JPEG has no alpha channel.
+      image::DynamicImage::ImageRgb8(decoded.to_rgb8()).write_with_encoder(
+        image::codecs::jpeg::JpegEncoder::new_with_quality(
+          &mut encoded,
+          quality.unwrap_or(90),
+        ),
+      )?
+    }
+    OutputFormat::Webp => decoded.write_with_encoder(
+      image::codecs::webp::WebPEncoder::new_lossless(&mut encoded),
+    )?,
+  }
+  Ok(encoded)
+}
+
+/// Converts to `bit_depth` bits per channel, keeping the image's depth when
+/// unset, and adds an alpha channel when `alpha` is set.
+fn convert_color(
+  image: image::DynamicImage,
+  bit_depth: Option<u8>,
+  alpha: bool,
+) -> image::DynamicImage {
+  use image::DynamicImage;
+  if bit_depth.is_none() && !alpha {
+    return image;
+  }
+  let color = image.color();
+  let wide = match bit_depth {
+    Some(bits) => bits == 16,
+    None => color.bytes_per_pixel() > color.channel_count(),
+  };
+  match (wide, alpha || color.has_alpha()) {
+    (false, false) => DynamicImage::ImageRgb8(image.to_rgb8()),
+    (false, true) => DynamicImage::ImageRgba8(image.to_rgba8()),
+    (true, false) => DynamicImage::ImageRgb16(image.to_rgb16()),
+    (true, true) => DynamicImage::ImageRgba16(image.to_rgba16()),
+  }
+}
+
+/// The generation parameters as Automatic1111 writes them, which most image
+/// tools can read back.
+pub fn generation_parameters(
+  context: &Context,
+  body: &ImageGenerationRequest,
+  resolved: &ResolvedRequest,
+  seed: i64,
+) -> String {
+  let mut parameters = resolved.prompt.clone();
+  if let Some(negative_prompt) = &resolved.negative_prompt {
+    parameters.push_str(&format!("\nNegative prompt: {}", negative_prompt));
+  }
+  parameters.push_str(&format!(
+    "\nSteps: {}, Sampler: {}, CFG scale: {}, Seed: {}, Size: {}, Model: {}",
+    effective_steps(context, body),
+    effective_sampler(body).unwrap_or("default"),
+    effective_cfg_scale(context, body),
+    seed,
+    body.size,
+    model_name(&resolved.model)
+  ));
+  if let Some(sha256) = &resolved.model_sha256 {
+    // Automatic1111's short hash.
+    parameters.push_str(&format!(", Model hash: {}", &sha256[..10]));
+  }
+  if let Some(strength) = resolved.strength {
+    parameters.push_str(&format!(", Denoising strength: {}", strength));
+  }
+  if let Some(refiner) = &resolved.refiner {
+    parameters.push_str(&format!(
+      ", Refiner: {}, Refiner switch at: {}",
+      model_name(refiner),
+      effective_refiner_switch_at(body)
+    ));
+  }
+  parameters
+}
+
+/// Inserts a `parameters` text chunk right after the PNG header, leaving
+/// the image data untouched. Text outside Latin-1 goes in an iTXt chunk.
+pub fn embed_parameters(
+  image_data: Vec<u8>,
+  parameters: &str,
+) -> Result<Vec<u8>, String> {
+  use png::text_metadata::{EncodableTextChunk, ITXtChunk, TEXtChunk};
+  // Signature, then the IHDR chunk: length, type, 13 bytes of data, CRC.
+  const IHDR_END: usize = 8 + 4 + 4 + 13 + 4;
+  if image_data.len() < IHDR_END || &image_data[12..16] != b"IHDR" {
+    return Err("not a PNG image".to_string());
+  }
+  let mut chunk = Vec::new();
+  if TEXtChunk::new("parameters", parameters)
+    .encode(&mut chunk)
+    .is_err()
+  {
+    chunk.clear();
+    ITXtChunk::new("parameters", parameters)
+      .encode(&mut chunk)
+      .map_err(|e| e.to_string())?;
+  }
+  let mut embedded = Vec::with_capacity(image_data.len() + chunk.len());
+  embedded.extend_from_slice(&image_data[..IHDR_END]);
+  embedded.extend_from_slice(&chunk);
+  embedded.extend_from_slice(&image_data[IHDR_END..]);
+  Ok(embedded)
+}
+
+/// Extensions recognized as model weights in `models_dir`, in the order they
+/// are tried when resolving a requested model name.
+pub const MODEL_EXTENSIONS: &[&str] = &["gguf", "safetensors", "ckpt", "pth"];
+
+/// Rejects user-controlled fields that cannot be safely handed to the binary.
+pub fn validate_request(
+  context: &Context,
+  body: &ImageGenerationRequest,
+) -> Result<(), ApiError> {
+  let invalid = |message: String| Err(ApiError::bad_request(message));
+  if body.n == 0 || body.n > context.max_images {
+    return invalid(format!("n must be between 1 and {}", context.max_images));
+  }
+  if body.prompt.trim().is_empty() {
+    return invalid("prompt must not be empty".to_string());
+  }
+  for (name, prompt) in [
+    ("prompt", Some(&body.prompt)),
+    ("negative_prompt", body.negative_prompt.as_ref()),
+  ] {
+    let length = prompt.map_or(0, |prompt| prompt.chars().count());
+    if length > context.max_prompt_chars {
+      return invalid(format!(
+        "{} must be at most {} characters, got {}",
+        name, context.max_prompt_chars, length
+      ));
+    }
+  }
+  if body.prompt.contains('\0') {
+    return invalid("prompt must not contain NUL characters".to_string());
+  }
+  if let Some(negative_prompt) = &body.negative_prompt {
+    if negative_prompt.contains('\0') {
+      return invalid(
+        "negative_prompt must not contain NUL characters".to_string(),
+      );
+    }
+  }
+  for (name, prompt) in [
+    ("prompt", Some(&body.prompt)),
+    ("negative_prompt", body.negative_prompt.as_ref()),
+  ] {
+    if let Some(Err(e)) = prompt.map(|prompt| check_prompt_weights(prompt)) {
+      return invalid(format!("{} {}", name, e));
+    }
+  }
+  if let Some(style) = &body.style {
+    if !context.settings().styles.contains_key(style) {
+      return invalid(format!("unknown style '{}'", style));
+    }
+  }
+  if let Some(backend) = &body.backend {
+    if !context.binaries.contains_key(backend) {
+      return invalid(format!("unknown backend '{}'", backend));
+    }
+  }
+  let Some((width, height)) = parse_size(&body.size) else {
+    return invalid(format!(
+      "invalid size '{}', expected WIDTHxHEIGHT",
+      body.size
+    ));
+  };
+  for (name, value) in [("width", width), ("height", height)] {
+    if !(MIN_DIMENSION..=context.max_dimension).contains(&value) {
+      return invalid(format!(
+        "{} must be between {} and {}, got {}",
+        name, MIN_DIMENSION, context.max_dimension, value
+      ));
+    }
+    if value % 8 != 0 {
+      return invalid(format!(
+        "{} must be a multiple of 8, got {}",
+        name, value
+      ));
+    }
+  }
+  if let Some(max_megapixels) = context.max_megapixels {
+    let megapixels = width as f64 * height as f64 / 1_000_000.0;
+    if megapixels > max_megapixels {
+      return invalid(format!(
+        "size {} is {:.2} megapixels, above the limit of {}",
+        body.size, megapixels, max_megapixels
+      ));
+    }
+  }
+  if body.seed.is_some_and(|seed| seed < 0) {
+    return invalid(
+      "seed must not be negative; omit it or pass -1 for a random seed"
+        .to_string(),
+    );
+  }
+  let steps = effective_steps(context, body);
+  if !(1..=context.max_steps).contains(&steps) {
+    return invalid(format!(
+      "steps must be between 1 and {}, got {}",
+      context.max_steps, steps
+    ));
+  }
+  if !(0.0..=MAX_CFG_SCALE).contains(&body.cfg_scale) {
+    return invalid(format!(
+      "cfg_scale must be between 0 and {}, got {}",
+      MAX_CFG_SCALE, body.cfg_scale
+    ));
+  }
+  if let Some(sampler) = &body.sampler {
+    if !SAMPLERS.contains(&sampler.as_str()) {
+      return invalid(format!(
+        "unknown sampler '{}', expected one of: {}",
+        sampler,
+        SAMPLERS.join(", ")
+      ));
+    }
+  }
+  if let Some(user) = &body.user {
+    if user.is_empty() || user.chars().count() > MAX_USER_CHARS {
+      return invalid(format!(
+        "user must be between 1 and {} characters",
+        MAX_USER_CHARS
+      ));
+    }
+    if user.chars().any(char::is_control) {
+      return invalid("user must not contain control characters".to_string());
+    }
+  }
+  if let Some(clip_skip) = body.clip_skip {
+    if !(1..=12).contains(&clip_skip) {
+      return invalid("clip_skip must be between 1 and 12".to_string());
+    }
+  }
+  if let Some(device) = body.device {
+    if context.devices.is_empty() {
+      return invalid(
+        "device selection is not enabled on this server".to_string(),
+      );
+    }
+    if device as usize >= context.devices.len() {
+      return invalid(format!(
+        "device must be below {}",
+        context.devices.len()
+      ));
+    }
+  }
+  if body.tiling
+    && (width % TILING_MULTIPLE != 0 || height % TILING_MULTIPLE != 0)
+  {
+    return invalid(format!(
+      "tiling requires a width and height in multiples of {}",
+      TILING_MULTIPLE
+    ));
+  }
+  if let (ResponseFormat::Zip, Some(_)) =
+    (&body.response_format, &body.webhook_url)
+  {
+    return invalid(
+      "response_format zip does not support webhook_url".to_string(),
+    );
+  }
+  if let Some(webhook_url) = &body.webhook_url {
+    let Some(allowed_hosts) = &context.webhook_allowed_hosts else {
+      return invalid("webhooks are not enabled on this server".to_string());
+    };
+    let url = match reqwest::Url::parse(webhook_url) {
+      Ok(url) if matches!(url.scheme(), "http" | "https") => url,
+      _ => {
+        return invalid("webhook_url must be an http or https URL".to_string())
+      }
+    };
+    let host = url.host_str().unwrap_or_default();
+    if !allowed_hosts.iter().any(|allowed| allowed == host) {
+      return invalid(format!("webhook host '{}' is not allowed", host));
+    }
+  }
+  if let Some(switch_at) = body.refiner_switch_at {
+    if body.refiner_model.is_none() {
+      return invalid("refiner_switch_at requires a refiner_model".to_string());
+    }
+    if !(0.0..=1.0).contains(&switch_at) {
+      return invalid(format!(
+        "refiner_switch_at must be between 0.0 and 1.0, got {}",
+        switch_at
+      ));
+    }
+  }
+  if body.dry_run && body.webhook_url.is_some() {
+    return invalid("dry_run does not support webhook_url".to_string());
+  }
+  if body.deterministic {
+    if body.seed.is_none() {
+      return invalid("deterministic requires a seed".to_string());
+    }
+    if body.threads.is_some_and(|threads| threads != 1) {
+      return invalid("deterministic runs on a single thread".to_string());
+    }
+  }
+  if let Some(threads) = body.threads {
+    let available =
+      std::thread::available_parallelism().map_or(1, |n| n.get() as u32);
+    if !(1..=available).contains(&threads) {
+      return invalid(format!("threads must be between 1 and {}", available));
+    }
+  }
+  validate_extra_args(context, &body.extra_args)
+    .map_err(ApiError::bad_request)?;
+  if let Some(bits) = body.bit_depth {
+    if bits != 8 && bits != 16 {
+      return invalid(format!("bit_depth must be 8 or 16, got {}", bits));
+    }
+  }
+  if body.background == Background::Transparent
+    && context.background_remover.is_none()
+  {
+    return invalid(
+      "background transparent is not enabled on this server".to_string(),
+    );
+  }
+  if body.formats.is_empty() {
+    if body.embed_metadata && body.output_format != OutputFormat::Png {
+      return invalid("embed_metadata requires output_format png".to_string());
+    }
+    let quality = body.quality.and_then(Quality::encoder);
+    return check_output_format(
+      body,
+      "output_format",
+      body.output_format,
+      quality,
+    )
+    .map_err(ApiError::bad_request);
+  }
+  if body.formats.len() > MAX_FORMATS {
+    return invalid(format!(
+      "formats holds at most {} entries, got {}",
+      MAX_FORMATS,
+      body.formats.len()
+    ));
+  }
+  if body.quality.and_then(Quality::encoder).is_some() {
+    return invalid("quality is set per entry of formats".to_string());
+  }
+  if let ResponseFormat::Zip = body.response_format {
+    return invalid("response_format zip does not support formats".to_string());
+  }
+  if body.embed_metadata
+    && !body
+      .formats
+      .iter()
+      .any(|spec| spec.format == OutputFormat::Png)
+  {
+    return invalid(
+      "embed_metadata requires a png entry in formats".to_string(),
+    );
+  }
+  for spec in &body.formats {
+    if let Some(max) = spec.max_dimension {
+      if !(MIN_DIMENSION..=context.max_dimension).contains(&max) {
+        return invalid(format!(
+          "formats max_dimension must be between {} and {}, got {}",
+          MIN_DIMENSION, context.max_dimension, max
+        ));
+      }
+    }
+    check_output_format(body, "formats", spec.format, spec.quality)
+      .map_err(ApiError::bad_request)?;
+  }
+  Ok(())
+}
+
+/// Most entries of `formats`, each encoding every image once more.
+const MAX_FORMATS: usize = 4;
+
+/// Checks the options of the request that only some formats support
+/// against an encoding of its images, named by `field`.
+fn check_output_format(
+  body: &ImageGenerationRequest,
+  field: &str,
+  format: OutputFormat,
+  quality: Option<u8>,
+) -> Result<(), String> {
+  if body.bit_depth == Some(16) && format != OutputFormat::Png {
+    return Err(format!(
+      "bit_depth 16 is not supported for {} {}",
+      field,
+      format.extension()
+    ));
+  }
+  if format == OutputFormat::Jpeg {
+    if body.alpha {
+      return Err(format!("alpha is not supported for {} jpeg", field));
+    }
+    if body.background == Background::Transparent {
+      return Err(format!(
+        "background transparent is not supported for {} jpeg",
+        field
+      ));
+    }
+  }
+  if let Some(quality) = quality {
+    if !format.is_lossy() {
+      return Err(format!(
+        "quality is not supported for {} {}",
+        field,
+        format.extension()
+      ));
+    }
+    if !(1..=100).contains(&quality) {
+      return Err("quality must be between 1 and 100".to_string());
+    }
+  }
+  Ok(())
+}
+
+/// Checks `extra_args` flag by flag against the server's allowlist. Other
+/// arguments are the values of the flag before them, and may only look
+/// like a flag when they are a negative number.
+fn validate_extra_args(
+  context: &Context,
+  args: &[String],
+) -> Result<(), String> {
+  if args.is_empty() {
+    return Ok(());
+  }
+  let Some(allowed) = &context.allowed_extra_flags else {
+    return Err("extra_args are not enabled on this server".to_string());
+  };
+  let mut after_flag = false;
+  for arg in args {
+    if arg.contains('\0') {
+      return Err("extra_args must not contain NUL characters".to_string());
+    }
+    let is_flag = arg.starts_with('-') && arg.parse::<f64>().is_err();
+    if !is_flag {
+      if !after_flag {
+        return Err(format!("extra_args value '{}' follows no flag", arg));
+      }
+      continue;
+    }
+    let flag = arg.split_once('=').map_or(arg.as_str(), |(flag, _)| flag);
+    if !allowed.iter().any(|allowed| allowed == flag) {
+      return Err(format!("extra_args flag '{}' is not allowed", flag));
+    }
+    after_flag = true;
+  }
+  Ok(())
+}
+
+/// The binary's flag for seamless images. Older binaries lack it.
+const TILING_FLAG: &str = "--circular";
+
+/// The UNet downsamples the 1/8 scale latents three more times, so a
+/// circular pad only lines up across the edges when both dimensions divide
+/// evenly all the way down.
+const TILING_MULTIPLE: u32 = 64;
+
+/// Largest accepted `cfg_scale`; guidance above it only degrades images.
+const MAX_CFG_SCALE: f32 = 30.0;
+
+/// Longest accepted `user`, which is written to every log line of the
+/// generation.
+const MAX_USER_CHARS: usize = 256;
+
+/// Smallest accepted width or height.
+pub const MIN_DIMENSION: u32 = 64;
+
+/// Parses a `WIDTHxHEIGHT` size such as `512x768`.
+pub fn parse_size(size: &str) -> Option<(u32, u32)> {
+  let (width, height) = size.split_once('x')?;
+  let parse = |part: &str| {
+    if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+      return None;
+    }
+    part.parse().ok()
+  };
+  Some((parse(width)?, parse(height)?))
+}
+
+/// Request values resolved against the server's directories, ready to be
+/// passed to the binary.
+pub struct ResolvedRequest {
+  /// Path of the model weights.
+  pub model: String,
+  /// SHA-256 of the weights, when requested by hash or already computed.
+  pub model_sha256: Option<String>,
+  /// User prompt with any LoRA tags appended.
+  pub prompt: String,
+  /// Negative prompt after applying the server default.
+  pub negative_prompt: Option<String>,
+  /// Arguments appended after the common generation flags.
+  pub extra_args: Vec<String>,
+  /// Strength of an edit, from 0.0 to 1.0.
+  pub strength: Option<f32>,
+  /// Reported in the metadata.
+  pub warnings: Vec<String>,
+  /// Path of the binary of the request's `backend`.
+  pub binary: String,
+  /// Path of the `refiner_model` weights.
+  pub refiner: Option<String>,
+  /// File holding `prompt` for `--prompt-file`, for commands too long to
+  /// pass it as an argument. Removed once the last run using it is done.
+  pub prompt_file: Option<Arc<TempFile>>,
+  /// The same for `negative_prompt` and `--negative-prompt-file`.
+  pub negative_prompt_file: Option<Arc<TempFile>>,
+}
+
+pub async fn resolve_request(
+  context: &Context,
+  body: &ImageGenerationRequest,
+) -> Result<ResolvedRequest, ApiError> {
+  resolve(context, body, true).await
+}
+
+/// `resolve_request` for the tiny throwaway generations of the self-test
+/// and warmups, which `strict_sizes` must not reject for being far from
+/// the model's `native_sizes`.
+pub async fn resolve_throwaway_request(
+  context: &Context,
+  body: &ImageGenerationRequest,
+) -> Result<ResolvedRequest, ApiError> {
+  resolve(context, body, false).await
+}
+
+async fn resolve(
+  context: &Context,
+  body: &ImageGenerationRequest,
+  check_sizes: bool,
+) -> Result<ResolvedRequest, ApiError> {
+  check_models_dir(context).await?;
+  let (model, model_sha256) = resolve_model(context, &body.model).await?;
+  let refiner = match &body.refiner_model {
+    Some(refiner) => Some(resolve_model(context, refiner).await?.0),
+    None => None,
+  };
+  let warnings = match check_sizes {
+    true => check_native_size(context, body, &model)?
+      .into_iter()
+      .collect(),
+    false => Vec::new(),
+  };
+  let style = body
+    .style
+    .as_ref()
+    .and_then(|name| context.settings().styles.get(name).cloned());
+  let mut prompt = match &style {
+    Some(style) => join_prompts([
+      style.prefix.as_deref(),
+      Some(body.prompt.as_str()),
+      style.suffix.as_deref(),
+    ]),
+    None => body.prompt.clone(),
+  };
+  for lora in &body.loras {
+    resolve_lora(context, lora).await?;
+    prompt.push_str(&format!(" <lora:{}:{}>", lora.name, lora.weight));
+  }
+  let binary = context.binary(body.backend.as_deref()).to_string();
+  if body.tiling && !binary_supports(context, &binary, TILING_FLAG).await {
+    return Err(ApiError::bad_request(format!(
+      "tiling requires a stable-diffusion.cpp binary supporting {}, \
+       update the server's binary",
+      TILING_FLAG
+    )));
+  }
+  let mut extra_args = body.extra_args.clone();
+  if let Some(vae) = &body.vae {
+    extra_args.push("--vae".to_string());
+    extra_args.push(resolve_vae(context, vae).await?);
+  }
+  let mut resolved = ResolvedRequest {
+    model,
+    model_sha256,
+    prompt,
+    negative_prompt: match &style {
+      Some(style) => Some(join_prompts([
+        negative_prompt(context, body).as_deref(),
+        style.negative.as_deref(),
+      ]))
+      .filter(|prompt| !prompt.is_empty()),
+      None => negative_prompt(context, body),
+    },
+    extra_args,
+    strength: None,
+    warnings,
+    binary,
+    refiner,
+    prompt_file: None,
+    negative_prompt_file: None,
+  };
+  pass_long_prompts(context, body, &mut resolved).await?;
+  Ok(resolved)
+}
+
+/// Budget for the whole command line passed to the binary, its arguments
+/// and environment together, as `command_len` counts them. Windows limits a
+/// whole command line to 32 KiB. Linux allows more in total but limits a
+/// single argument to 128 KiB, so a command within that total cannot hold
+/// an argument over the limit either.
+const MAX_COMMAND_LEN: usize = if cfg!(windows) { 32 } else { 128 } * 1024;
+
+/// Room left on the command line for the output path and arguments added
+/// after `resolve_request`, such as the refiner's input image.
+const COMMAND_LEN_MARGIN: usize = 4 * 1024;
+
+const PROMPT_FILE_FLAG: &str = "--prompt-file";
+
+const NEGATIVE_PROMPT_FILE_FLAG: &str = "--negative-prompt-file";
+
+/// Moves the prompts to files, as far as the binary supports it, when they
+/// would not leave the command line under `MAX_COMMAND_LEN`. Otherwise they
+/// are left inline, which fails to spawn if the command is really too long.
+async fn pass_long_prompts(
+  context: &Context,
+  body: &ImageGenerationRequest,
+  resolved: &mut ResolvedRequest,
+) -> Result<(), ApiError> {
+  let cmd = build_command(context, body, resolved, 0, &context.cache_dir)?;
+  if command_len(&cmd) + COMMAND_LEN_MARGIN <= MAX_COMMAND_LEN {
+    return Ok(());
+  }
+  if binary_supports(context, &resolved.binary, PROMPT_FILE_FLAG).await {
+    let file = write_prompt_file(context, &resolved.prompt).await?;
+    resolved.prompt_file = Some(Arc::new(file));
+  }
+  if let Some(negative_prompt) = &resolved.negative_prompt {
+    if binary_supports(context, &resolved.binary, NEGATIVE_PROMPT_FILE_FLAG)
+      .await
+    {
+      let file = write_prompt_file(context, negative_prompt).await?;
+      resolved.negative_prompt_file = Some(Arc::new(file));
+    }
+  }
+  if resolved.prompt_file.is_none()
+    || resolved.negative_prompt.is_some()
+      && resolved.negative_prompt_file.is_none()
+  {
+    tracing::warn!(
+      prompt_len = resolved.prompt.len(),
+      in_file = resolved.prompt_file.is_some(),
+      negative_in_file = resolved.negative_prompt_file.is_some(),
+      "long command line, the binary cannot take every prompt from a file"
+    );
+  }
+  Ok(())
+}
+
+/// Bytes the command takes in the argument and environment space of the
+/// new process.
+fn command_len(cmd: &Command) -> usize {
+  let cmd = cmd.as_std();
+  let args = std::iter::once(cmd.get_program())
+    .chain(cmd.get_args())
+    .map(|arg| arg.len() + 1)
+    .sum::<usize>();
+  let env = std::env::vars_os()
+    .map(|(key, value)| (key, Some(value)))
+    .chain(
+      cmd
+        .get_envs()
+        .map(|(key, value)| (key.into(), value.map(Into::into))),
+    )
+    .map(|(key, value)| key.len() + value.map_or(0, |value| value.len()) + 2)
+    .sum::<usize>();
+  args + env
+}
+
+async fn write_prompt_file(
+  context: &Context,
+  prompt: &str,
+) -> Result<TempFile, ApiError> {
+  let file = TempFile::new(format!(
+    "{}/{}{}_prompt.txt",
+    context.cache_dir,
+    INPUT_PREFIX,
+    unique_name()
+  ));
+  if let Err(e) = tokio::fs::write(file.path(), prompt).await {
+    tracing::error!(error = %e, "failed to write prompt file");
+    context.metrics.record_failure("server_error");
+    return Err(ApiError::server_error(format!(
+      "Failed to write prompt file: {}",
+      e
+    )));
+  }
+  Ok(file)
+}
+
+/// Sizes whose area is this many times below the smallest native size of
+/// the model, or above its largest, are far off enough to duplicate
+/// subjects or blur.
+const NATIVE_SIZE_TOLERANCE: u64 = 2;
+
+/// Checks `size` against the `native_sizes` of the model, returning a
+/// warning suggesting the native size closest in aspect ratio when it is
+/// far off, or failing with `strict_sizes`.
+fn check_native_size(
+  context: &Context,
+  body: &ImageGenerationRequest,
+  model: &str,
+) -> Result<Option<String>, ApiError> {
+  let name = model_name(model);
+  let settings = context.settings();
+  let sizes = settings.native_sizes.get(name).or_else(|| {
+    settings
+      .native_sizes
+      .iter()
+      .filter_map(|(pattern, sizes)| {
+        let prefix = pattern.strip_suffix('*')?;
+        name.starts_with(prefix).then_some((prefix.len(), sizes))
+      })
+      .max_by_key(|(len, _)| *len)
+      .map(|(_, sizes)| sizes)
+  });
+  let (Some(sizes), Some((width, height))) = (sizes, parse_size(&body.size))
+  else {
+    return Ok(None);
+  };
+  let area = |(width, height): (u32, u32)| width as u64 * height as u64;
+  let smallest = sizes.iter().copied().map(area).min().unwrap_or(0);
+  let largest = sizes.iter().copied().map(area).max().unwrap_or(0);
+  let requested = area((width, height));
+  if requested * NATIVE_SIZE_TOLERANCE >= smallest
+    && requested <= largest * NATIVE_SIZE_TOLERANCE
+  {
+    return Ok(None);
+  }
+  let aspect =
+    |(width, height): (u32, u32)| (width as f64 / height as f64).ln();
+  let (suggested_width, suggested_height) = sizes
+    .iter()
+    .copied()
+    .min_by(|a, b| {
+      let distance = |size| (aspect(size) - aspect((width, height))).abs();
+      distance(*a).total_cmp(&distance(*b))
+    })
+    .unwrap_or((width, height));
+  let warning = format!(
+    "size {} is far from the native resolutions of model '{}', try {}x{}",
+    body.size, name, suggested_width, suggested_height
+  );
+  if context.strict_sizes {
+    return Err(ApiError::bad_request(warning));
+  }
+  tracing::warn!(size = %body.size, model = name, "size far from native");
+  Ok(Some(warning))
+}
+
+/// Whether `binary --help` lists `flag`. The help is read once it succeeds,
+/// so a binary replaced while the server runs is not seen.
+async fn binary_supports(context: &Context, binary: &str, flag: &str) -> bool {
+  let cached = context.binary_help.lock().unwrap().get(binary).cloned();
+  let help = match cached {
+    Some(help) => help,
+    None => {
+      let run = run_output(
+        Command::new(binary).arg("--help"),
+        Some(Duration::from_secs(10)),
+      )
+      .await;
+      let Ok(output) = run else {
+        return false;
+      };
+      let help = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+      );
+      context
+        .binary_help
+        .lock()
+        .unwrap()
+        .insert(binary.to_string(), help.clone());
+      help
+    }
+  };
+  help
+    .split(|c: char| c.is_whitespace() || c == ',')
+    .any(|word| word == flag)
+}
+
+/// The first line `binary --version` prints. Builds without the flag exit
+/// with an error, giving `None`.
+pub async fn binary_version(binary_path: &str) -> Option<String> {
+  let output = run_output(
+    Command::new(binary_path).arg("--version"),
+    Some(Duration::from_secs(10)),
+  )
+  .await
+  .ok()?;
+  if !output.status.success() {
+    return None;
+  }
+  String::from_utf8_lossy(&output.stdout)
+    .lines()
+    .map(str::trim)
+    .find(|line| !line.is_empty())
+    .map(str::to_string)
+}
+
+/// Joins the non-empty prompt fragments with commas.
+fn join_prompts<'a>(
+  parts: impl IntoIterator<Item = Option<&'a str>>,
+) -> String {
+  parts
+    .into_iter()
+    .flatten()
+    .filter(|part| !part.trim().is_empty())
+    .collect::<Vec<_>>()
+    .join(", ")
+}
+
+/// Applies `SD_CPP_SERVER_DEFAULT_NEGATIVE_PROMPT`: it is used when the
+/// request has no `negative_prompt`, replaced by the request's one unless
+/// `append_negative` is set, and dropped when the request sends an empty
+/// string.
+fn negative_prompt(
+  context: &Context,
+  body: &ImageGenerationRequest,
+) -> Option<String> {
+  let default = context.settings().default_negative_prompt.clone();
+  match (body.negative_prompt.as_deref(), default.as_deref()) {
+    (Some(""), _) => None,
+    (Some(prompt), Some(default)) if body.append_negative => {
+      Some(format!("{}, {}", default, prompt))
+    }
+    (Some(prompt), _) => Some(prompt.to_string()),
+    (None, default) => default.map(str::to_string),
+  }
+}
+
+/// Checks that a requested LoRA exists in `loras_dir` with a sane weight.
+async fn resolve_lora(
+  context: &Context,
+  lora: &LoraSpec,
+) -> Result<(), ApiError> {
+  let Some(loras_dir) = &context.loras_dir else {
+    return Err(ApiError::bad_request(
+      "LoRAs are not enabled on this server".to_string(),
+    ));
+  };
+  // `:` and `>` would break out of the `<lora:name:weight>` prompt tag.
+  if !is_safe_name(&lora.name) || lora.name.contains([':', '>', '<']) {
+    return Err(ApiError::bad_request(format!(
+      "invalid LoRA name '{}'",
+      lora.name
+    )));
+  }
+  if !(-2.0..=2.0).contains(&lora.weight) {
+    return Err(ApiError::bad_request(format!(
+      "LoRA '{}' weight must be between -2.0 and 2.0",
+      lora.name
+    )));
+  }
+  match find_file(loras_dir, &lora.name, LORA_EXTENSIONS).await {
+    Some(_) => Ok(()),
+    None => Err(ApiError::bad_request(format!(
+      "LoRA '{}' not found",
+      lora.name
+    ))),
+  }
+}
+
+/// Resolves a requested VAE name to a file in `vaes_dir`.
+async fn resolve_vae(
+  context: &Context,
+  name: &str,
+) -> Result<String, ApiError> {
+  let Some(vaes_dir) = &context.vaes_dir else {
+    return Err(ApiError::bad_request(
+      "VAE overrides are not enabled on this server",
+    ));
+  };
+  if !is_safe_name(name) {
+    return Err(ApiError::bad_request(format!(
+      "invalid VAE name '{}'",
+      name
+    )));
+  }
+  find_file(vaes_dir, name, MODEL_EXTENSIONS)
+    .await
+    .ok_or_else(|| ApiError::bad_request(format!("VAE '{}' not found", name)))
+}
+
+/// Resolves a requested ControlNet name to a file in `controlnets_dir`.
+pub async fn resolve_controlnet(
+  context: &Context,
+  name: &str,
+) -> Result<String, ApiError> {
+  let Some(controlnets_dir) = &context.controlnets_dir else {
+    return Err(ApiError::bad_request(
+      "ControlNet is not enabled on this server",
+    ));
+  };
+  if !is_safe_name(name) {
+    return Err(ApiError::bad_request(format!(
+      "invalid ControlNet name '{}'",
+      name
+    )));
+  }
+  find_file(controlnets_dir, name, MODEL_EXTENSIONS)
+    .await
+    .ok_or_else(|| {
+      ApiError::bad_request(format!("ControlNet '{}' not found", name))
+    })
+}
+
+/// Extensions the binary looks for when loading a LoRA from
+/// `--lora-model-dir`.
+const LORA_EXTENSIONS: &[&str] = &["safetensors", "ckpt", "gguf"];
+
+/// How long the outcome of `check_models_dir` is reused.
+const MODELS_DIR_CHECK_TTL: Duration = Duration::from_secs(5);
+
+/// Fails with a 503 while `models_dir` is unreadable, such as a network
+/// mount gone away, rather than reporting every model as missing.
+async fn check_models_dir(context: &Context) -> Result<(), ApiError> {
+  let cached = *context.models_dir_checked.lock().unwrap();
+  let readable = match cached {
+    Some((checked, readable)) if checked.elapsed() < MODELS_DIR_CHECK_TTL => {
+      readable
+    }
+    _ => {
+      let readable = match tokio::fs::read_dir(&context.models_dir).await {
+        Ok(_) => true,
+        Err(e) => {
+          tracing::error!(error = %e, "models directory is not readable");
+          false
+        }
+      };
+      *context.models_dir_checked.lock().unwrap() =
+        Some((Instant::now(), readable));
+      readable
+    }
+  };
+  if readable {
+    return Ok(());
+  }
+  context.metrics.record_failure("service_unavailable");
+  Err(
+    ApiError::new(
+      StatusCode::SERVICE_UNAVAILABLE,
+      "Models are temporarily unavailable, retry later",
+      "service_unavailable",
+    )
+    .with_retry_after(30),
+  )
+}
+
+/// Prefix of a model requested by the SHA-256 of its weights rather than by
+/// name.
+const MODEL_HASH_PREFIX: &str = "sha256:";
+
+/// Resolves a requested model to a weights file in `models_dir` and its
+/// hash, if known. A model named `sha256:<hex>` is looked up by hash.
+async fn resolve_model(
+  context: &Context,
+  name: &str,
+) -> Result<(String, Option<String>), ApiError> {
+  let (path, sha256) = match name.strip_prefix(MODEL_HASH_PREFIX) {
+    Some(hash) => {
+      let hash = hash.to_ascii_lowercase();
+      (find_model_by_hash(context, &hash).await?, Some(hash))
+    }
+    None => {
+      let path = find_model(context, name).await?;
+      let sha256 = cached_model_sha256(context, &path).await;
+      (path, sha256)
+    }
+  };
+  if !has_model_magic(&path).await {
+    return Err(ApiError::bad_request(format!(
+      "model '{}' has an unsupported format",
+      name
+    )));
+  }
+  Ok((path, sha256))
+}
+
+/// Finds a model by name, trying each of `MODEL_EXTENSIONS` in turn, and
+/// downloading it from `models_store` when missing.
+async fn find_model(context: &Context, name: &str) -> Result<String, ApiError> {
+  if !is_safe_name(name) {
+    return Err(ApiError::bad_request(format!(
+      "invalid model name '{}'",
+      name
+    )));
+  }
+  if !context.is_model_allowed(name) {
+    return Err(ApiError::new(
+      StatusCode::FORBIDDEN,
+      format!("model '{}' is not allowed", name),
+      "permission_error",
+    ));
+  }
+  let path = match find_file(&context.models_dir, name, MODEL_EXTENSIONS).await
+  {
+    Some(path) => Some(path),
+    None => download_model(context, name).await?,
+  }
+  .ok_or_else(|| {
+    ApiError::bad_request(format!("model '{}' not found", name))
+  })?;
+  Ok(path)
+}
+
+/// Finds the model in `models_dir` whose weights hash to `hash`. Every
+/// model is hashed on the first lookup, then only when it changes.
+async fn find_model_by_hash(
+  context: &Context,
+  hash: &str,
+) -> Result<String, ApiError> {
+  if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+    return Err(ApiError::bad_request(format!(
+      "invalid model hash '{}', expected 64 hexadecimal digits",
+      hash
+    )));
+  }
+  let mut entries = tokio::fs::read_dir(&context.models_dir)
+    .await
+    .map_err(|e| ApiError::server_error(e.to_string()))?;
+  while let Ok(Some(entry)) = entries.next_entry().await {
+    let file_name = entry.file_name().to_string_lossy().into_owned();
+    let Some((stem, ext)) = file_name.rsplit_once('.') else {
+      continue;
+    };
+    if !MODEL_EXTENSIONS.contains(&ext) {
+      continue;
+    }
+    let path = format!("{}/{}", context.models_dir, file_name);
+    match model_sha256(context, &path).await {
+      Ok(sha256) if sha256 == hash => {
+        if !context.is_model_allowed(stem) {
+          return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            format!("model '{}' is not allowed", stem),
+            "permission_error",
+          ));
+        }
+        return Ok(path);
+      }
+      Ok(_) => {}
+      Err(e) => {
+        tracing::warn!(error = %e, path = %path, "failed to hash model")
+      }
+    }
+  }
+  Err(ApiError::not_found(format!(
+    "no model has the hash '{}'",
+    hash
+  )))
+}
+
+/// The hash of a model if it was computed since the file last changed.
+async fn cached_model_sha256(context: &Context, path: &str) -> Option<String> {
+  let metadata = tokio::fs::metadata(path).await.ok()?;
+  let modified = metadata.modified().ok()?;
+  let hashes = context.model_hashes.lock().unwrap();
+  hashes
+    .get(path)
+    .filter(|hash| hash.modified == modified && hash.len == metadata.len())
+    .map(|hash| hash.sha256.clone())
+}
+
+/// The SHA-256 of a model's weights, read in a blocking task as models
+/// weigh gigabytes.
+async fn model_sha256(
+  context: &Context,
+  path: &str,
+) -> std::io::Result<String> {
+  if let Some(sha256) = cached_model_sha256(context, path).await {
+    return Ok(sha256);
+  }
+  let metadata = tokio::fs::metadata(path).await?;
+  let modified = metadata.modified()?;
+  let file = path.to_string();
+  let sha256 = tokio::task::spawn_blocking(move || {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(&file)?, &mut hasher)?;
+    Ok::<_, std::io::Error>(format!("{:x}", hasher.finalize()))
+  })
+  .await
+  .map_err(std::io::Error::other)??;
+  context.model_hashes.lock().unwrap().insert(
+    path.to_string(),
+    ModelHash {
+      modified,
+      len: metadata.len(),
+      sha256: sha256.clone(),
+    },
+  );
+  Ok(sha256)
+}
+
+/// Downloads a model missing from `models_dir` from `models_store`, trying
+/// each of `MODEL_EXTENSIONS` in turn.
+async fn download_model(
+  context: &Context,
+  name: &str,
+) -> Result<Option<String>, ApiError> {
+  let Some(store) = &context.models_store else {
+    return Ok(None);
+  };
+  for ext in MODEL_EXTENSIONS {
+    let file = format!("{}.{}", name, ext);
+    let path = format!("{}/{}", context.models_dir, file);
+    tracing::info!(file = %file, "downloading model");
+    match store.download(&file, &path).await {
+      Ok(true) => return Ok(Some(path)),
+      Ok(false) => continue,
+      Err(e) => {
+        tracing::error!(error = %e, file = %file, "failed to download model");
+        context.metrics.record_failure("service_unavailable");
+        return Err(
+          ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("Failed to download model '{}', retry later", name),
+            "service_unavailable",
+          )
+          .with_retry_after(30),
+        );
+      }
+    }
+  }
+  Ok(None)
+}
+
+/// Whether the file starts like weights in the format its extension names,
+/// so text files or truncated downloads fail before the binary loads them.
+pub async fn has_model_magic(path: &str) -> bool {
+  use tokio::io::AsyncReadExt;
+  let Ok(file) = tokio::fs::File::open(path).await else {
+    return false;
+  };
+  let mut header = Vec::with_capacity(9);
+  if file.take(9).read_to_end(&mut header).await.is_err() {
+    return false;
+  }
+  match path.rsplit_once('.').map(|(_, ext)| ext) {
+    Some("gguf") => header.starts_with(b"GGUF"),
+    // A little-endian header length, then the JSON header.
+    Some("safetensors") => header.get(8) == Some(&b'{'),
+    // PyTorch checkpoints are zip archives, or pickles in the legacy
+    // format.
+    _ => header.starts_with(b"PK\x03\x04") || header.first() == Some(&0x80),
+  }
+}
+
+/// Returns the path of the first `dir/name.ext` that is a file, trying
+/// `extensions` in order.
+pub async fn find_file(
+  dir: &str,
+  name: &str,
+  extensions: &[&str],
+) -> Option<String> {
+  for ext in extensions {
+    let path = format!("{}/{}.{}", dir, name, ext);
+    if tokio::fs::metadata(&path).await.is_ok_and(|m| m.is_file()) {
+      return Some(path);
+    }
+  }
+  None
+}
+
+fn file_name(path: &str) -> &str {
+  path.rsplit_once('/').map_or(path, |(_, name)| name)
+}
+
+/// The name a model is requested by, its file name without extension.
+fn model_name(path: &str) -> &str {
+  let name = file_name(path);
+  name.rsplit_once('.').map_or(name, |(stem, _)| stem)
+}
+
+/// Whether a client-supplied file name stays inside the directory it is
+/// joined to.
+pub fn is_safe_name(name: &str) -> bool {
+  !name.is_empty()
+    && !name.contains('/')
+    && !name.contains('\\')
+    && !name.contains("..")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::models_dir;
+  #[cfg(unix)]
+  use crate::testing::{fake_binary, fake_context, image_binary};
+
+  const CONFIG: &str = r#"
+    port = 8080
+    token = "t"
+    binary_path = "/opt/sd"
+    models_dir = "/models"
+  "#;
+
+  fn request(fields: serde_json::Value) -> ImageGenerationRequest {
+    let mut body = serde_json::json!({ "prompt": "a cat", "model": "foo" });
+    body
+      .as_object_mut()
+      .unwrap()
+      .extend(fields.as_object().unwrap().clone());
+    serde_json::from_value(body).unwrap()
+  }
+
+  fn resolved(body: &ImageGenerationRequest) -> ResolvedRequest {
+    ResolvedRequest {
+      model: "/models/foo.gguf".to_string(),
+      model_sha256: None,
+      prompt: body.prompt.clone(),
+      negative_prompt: body.negative_prompt.clone(),
+      extra_args: body.extra_args.clone(),
+      strength: None,
+      warnings: Vec::new(),
+      binary: "/opt/sd".to_string(),
+      refiner: None,
+      prompt_file: None,
+      negative_prompt_file: None,
+    }
+  }
+
+  fn args(context: &Context, body: &ImageGenerationRequest) -> Vec<String> {
+    let cmd = build_command(context, body, &resolved(body), 42, "/tmp/out.png")
+      .unwrap();
+    assert_eq!(cmd.as_std().get_program(), "/opt/sd");
+    cmd
+      .as_std()
+      .get_args()
+      .map(|arg| arg.to_string_lossy().into_owned())
+      .collect()
+  }
+
+  fn invalid(context: &Context, fields: serde_json::Value) -> String {
+    match validate_request(context, &request(fields)) {
+      Ok(()) => panic!("request accepted"),
+      Err(e) => e.message().to_string(),
+    }
+  }
+
+  #[test]
+  fn build_command_passes_the_defaults() {
+    let context = Context::for_tests(CONFIG);
+    assert_eq!(
+      args(&context, &request(serde_json::json!({}))),
+      [
+        "-m",
+        "/models/foo.gguf",
+        "-p",
+        "a cat",
+        "-o",
+        "/tmp/out.png",
+        "--steps",
+        "20",
+        "--cfg-scale",
+        "7",
+        "--seed",
+        "42",
+        "-W",
+        "512",
+        "-H",
+        "512",
+      ]
+    );
+  }
+
+  #[test]
+  fn build_command_passes_every_option() {
+    let context = Context::for_tests(&format!(
+      "{}args = \"--rng cuda\"\ndiffusion = true\nloras_dir = \"/loras\"",
+      CONFIG
+    ));
+    let body = request(serde_json::json!({
+      "prompt": "-a cat",
+      "negative_prompt": "--blurry",
+      "size": "768x512",
+      "steps": 30,
+      "cfg_scale": 4.5,
+      "sampler": "dpm++2m",
+      "clip_skip": 2,
+      "threads": 3,
+      "vae_tiling": true,
+      "diffusion_fa": true,
+      "vae_on_cpu": true,
+      "tiling": true,
+      "loras": [{ "name": "style", "weight": 0.5 }],
+      "extra_args": ["--control-strength", "0.8"],
+    }));
+    assert_eq!(
+      args(&context, &body),
+      [
+        "--rng",
+        "cuda",
+        "--diffusion-model",
+        "/models/foo.gguf",
+        "-p",
+        "-a cat",
+        "-o",
+        "/tmp/out.png",
+        "--steps",
+        "30",
+        "--cfg-scale",
+        "4.5",
+        "--seed",
+        "42",
+        "--threads",
+        "3",
+        "-n",
+        "--blurry",
+        "--sampling-method",
+        "dpm++2m",
+        "--clip-skip",
+        "2",
+        "-W",
+        "768",
+        "-H",
+        "512",
+        "--vae-tiling",
+        "--diffusion-fa",
+        "--vae-on-cpu",
+        "--circular",
+        "--lora-model-dir",
+        "/loras",
+        "--control-strength",
+        "0.8",
+      ]
+    );
+  }
+
+  #[test]
+  fn build_command_pins_deterministic_runs() {
+    let context = Context::for_tests(&format!("{}threads = 8", CONFIG));
+    let args = args(
+      &context,
+      &request(serde_json::json!({ "deterministic": true })),
+    );
+    let after = |flag: &str| {
+      let index = args.iter().position(|arg| arg == flag).unwrap();
+      args[index + 1].clone()
+    };
+    assert_eq!(after("--threads"), "1");
+    assert_eq!(after("--sampling-method"), DETERMINISTIC_SAMPLER);
+  }
+
+  #[test]
+  fn build_command_selects_the_device() {
+    let context = Context::for_tests(&format!("{}gpu_count = 2", CONFIG));
+    let body = request(serde_json::json!({ "device": 1 }));
+    let cmd =
+      build_command(&context, &body, &resolved(&body), 42, "/tmp/out.png")
+        .unwrap();
+    let envs: Vec<_> = cmd.as_std().get_envs().collect();
+    assert!(envs.contains(&(
+      std::ffi::OsStr::new("CUDA_VISIBLE_DEVICES"),
+      Some(std::ffi::OsStr::new("1"))
+    )));
+  }
+
+  #[test]
+  fn validate_request_accepts_a_plain_request() {
+    let context = Context::for_tests(CONFIG);
+    assert!(validate_request(&context, &request(serde_json::json!({}))).is_ok());
+  }
+
+  #[test]
+  fn validate_request_checks_sizes() {
+    let context = Context::for_tests(CONFIG);
+    let size =
+      |size: &str| invalid(&context, serde_json::json!({ "size": size }));
+    assert!(size("512").starts_with("invalid size"));
+    assert!(size("+512x512").starts_with("invalid size"));
+    assert!(size("32x512").starts_with("width must be between"));
+    assert!(size("512x4096").starts_with("height must be between"));
+    assert!(size("516x512").contains("multiple of 8"));
+  }
+
+  #[test]
+  fn validate_request_checks_the_megapixel_limit() {
+    let context =
+      Context::for_tests(&format!("{}max_megapixels = 0.5", CONFIG));
+    let message = invalid(&context, serde_json::json!({ "size": "1024x1024" }));
+    assert!(message.contains("above the limit of 0.5"), "{}", message);
+  }
+
+  #[test]
+  fn validate_request_checks_prompts() {
+    let context =
+      Context::for_tests(&format!("{}max_prompt_chars = 10", CONFIG));
+    assert!(invalid(&context, serde_json::json!({ "prompt": " " }))
+      .contains("must not be empty"));
+    assert!(
+      invalid(&context, serde_json::json!({ "prompt": "a".repeat(11) }))
+        .contains("at most 10 characters")
+    );
+    assert!(
+      invalid(&context, serde_json::json!({ "prompt": "a\0" })).contains("NUL")
+    );
+    assert!(
+      invalid(&context, serde_json::json!({ "negative_prompt": "(x" }))
+        .starts_with("negative_prompt has an unclosed '('")
+    );
+  }
+
+  #[test]
+  fn validate_request_checks_seeds_and_steps() {
+    let context = Context::for_tests(CONFIG);
+    assert!(invalid(&context, serde_json::json!({ "seed": -2 }))
+      .starts_with("seed must not be negative"));
+    assert!(validate_request(
+      &context,
+      &request(serde_json::json!({ "seed": -1 }))
+    )
+    .is_ok());
+    assert!(invalid(&context, serde_json::json!({ "steps": 0 }))
+      .starts_with("steps must be between"));
+    assert!(
+      invalid(&context, serde_json::json!({ "deterministic": true }))
+        .contains("requires a seed")
+    );
+    assert!(invalid(&context, serde_json::json!({ "n": 0 }))
+      .starts_with("n must be between"));
+  }
+
+  #[test]
+  fn validate_request_checks_output_formats() {
+    let context = Context::for_tests(CONFIG);
+    assert_eq!(
+      invalid(&context, serde_json::json!({ "quality": 80 })),
+      "quality is not supported for output_format png"
+    );
+    assert_eq!(
+      invalid(
+        &context,
+        serde_json::json!({ "output_format": "jpeg", "bit_depth": 16 })
+      ),
+      "bit_depth 16 is not supported for output_format jpeg"
+    );
+    assert_eq!(
+      invalid(
+        &context,
+        serde_json::json!({ "output_format": "jpeg", "alpha": true })
+      ),
+      "alpha is not supported for output_format jpeg"
+    );
+    assert_eq!(
+      invalid(&context, serde_json::json!({ "background": "transparent" })),
+      "background transparent is not enabled on this server"
+    );
+    assert_eq!(
+      invalid(
+        &context,
+        serde_json::json!({ "formats": [{ "format": "png", "quality": 50 }] })
+      ),
+      "quality is not supported for formats png"
+    );
+    let formats = vec![serde_json::json!({ "format": "png" }); 5];
+    assert!(invalid(&context, serde_json::json!({ "formats": formats }))
+      .starts_with("formats holds at most"));
+  }
+
+  #[test]
+  fn validate_request_checks_extra_args() {
+    let context = Context::for_tests(CONFIG);
+    let extra_args = serde_json::json!({ "extra_args": ["--rng", "cuda"] });
+    assert_eq!(
+      invalid(&context, extra_args.clone()),
+      "extra_args are not enabled on this server"
+    );
+    let context = Context::for_tests(&format!(
+      "{}allowed_extra_flags = \"--control-strength\"",
+      CONFIG
+    ));
+    assert!(validate_request(
+      &context,
+      &request(serde_json::json!({
+        "extra_args": ["--control-strength", "-0.5"]
+      }))
+    )
+    .is_ok());
+    assert!(validate_request(&context, &request(extra_args)).is_err());
+  }
+
+  #[test]
+  fn validate_request_checks_webhook_urls() {
+    let webhook = |url: &str| serde_json::json!({ "webhook_url": url });
+    let context = Context::for_tests(CONFIG);
+    assert_eq!(
+      invalid(&context, webhook("https://hooks.example.com/done")),
+      "webhooks are not enabled on this server"
+    );
+    let context = Context::for_tests(&format!(
+      "{}webhook_allowed_hosts = \"hooks.example.com\"",
+      CONFIG
+    ));
+    assert!(validate_request(
+      &context,
+      &request(webhook("https://hooks.example.com/done"))
+    )
+    .is_ok());
+    assert_eq!(
+      invalid(&context, webhook("https://169.254.169.254/latest")),
+      "webhook host '169.254.169.254' is not allowed"
+    );
+    assert_eq!(
+      invalid(&context, webhook("https://hooks.example.com.evil.test/")),
+      "webhook host 'hooks.example.com.evil.test' is not allowed"
+    );
+    for url in ["file:///etc/passwd", "ftp://hooks.example.com/", "hooks"] {
+      assert_eq!(
+        invalid(&context, webhook(url)),
+        "webhook_url must be an http or https URL"
+      );
+    }
+  }
+
+  #[test]
+  fn prompt_weights_must_balance() {
+    assert!(check_prompt_weights("a (cat:1.2), [dog]").is_ok());
+    assert!(check_prompt_weights(r"a \(literal").is_ok());
+    assert!(check_prompt_weights("(a:1:2)").is_ok());
+    assert_eq!(
+      check_prompt_weights("a (cat").unwrap_err(),
+      "has an unclosed '(' at position 2"
+    );
+    assert!(check_prompt_weights("a cat)").is_err());
+    assert!(check_prompt_weights("(a]").is_err());
+    // Only weights that look numeric are checked, so colons stay usable.
+    assert!(check_prompt_weights("(time: noon)").is_ok());
+    assert!(check_prompt_weights("(ratio 3:2 landscape)").is_ok());
+    assert_eq!(
+      check_prompt_weights("(cat:1.2.3)").unwrap_err(),
+      "has an invalid weight '1.2.3' at position 5"
+    );
+    assert!(check_prompt_weights("(cat: -0.5)").is_ok());
+    // Emoticons are text, not brackets.
+    assert!(check_prompt_weights("happy :)").is_ok());
+    assert!(check_prompt_weights("so sad :-( or not ;]").is_ok());
+    assert!(check_prompt_weights("(happy :):1.2)").is_ok());
+  }
+
+  #[test]
+  fn parse_size_only_takes_digits() {
+    assert_eq!(parse_size("768x512"), Some((768, 512)));
+    assert_eq!(parse_size("768X512"), None);
+    assert_eq!(parse_size("x512"), None);
+    assert_eq!(parse_size("-8x512"), None);
+  }
+
+  #[test]
+  fn batch_seeds_wrap_to_positive() {
+    assert_eq!(batch_seed(10, 2), 12);
+    assert_eq!(batch_seed(i64::MAX, 1), 0);
+  }
+
+  #[test]
+  fn cache_key_covers_the_inputs() {
+    let context = Context::for_tests(CONFIG);
+    let body = request(serde_json::json!({}));
+    let key = cache_key(&context, &body, &resolved(&body), 1);
+    assert_eq!(key, cache_key(&context, &body, &resolved(&body), 1));
+    assert_ne!(key, cache_key(&context, &body, &resolved(&body), 2));
+    for fields in [
+      serde_json::json!({ "prompt": "a dog" }),
+      serde_json::json!({ "size": "512x768" }),
+      serde_json::json!({ "steps": 21 }),
+      serde_json::json!({ "sampler": "euler" }),
+      serde_json::json!({ "vae_tiling": true }),
+    ] {
+      let other = request(fields.clone());
+      assert_ne!(
+        key,
+        cache_key(&context, &other, &resolved(&other), 1),
+        "{} does not change the key",
+        fields
+      );
+    }
+    // Only what reaches the binary counts.
+    let other = request(serde_json::json!({ "response_format": "url" }));
+    assert_eq!(key, cache_key(&context, &other, &resolved(&other), 1));
+  }
+
+  #[tokio::test]
+  async fn throwaway_runs_skip_strict_native_sizes() {
+    let dir = models_dir();
+    let context = Context::for_tests(&format!(
+      r#"
+        port = 8080
+        token = "t"
+        binary_path = "/opt/sd"
+        models_dir = "{}"
+        strict_sizes = true
+        [native_sizes]
+        "foo" = ["1024x1024"]
+      "#,
+      dir.display()
+    ));
+    let body = request(serde_json::json!({ "size": "64x64", "steps": 1 }));
+    let Err(error) = resolve_request(&context, &body).await else {
+      panic!("size accepted");
+    };
+    assert!(error.message().contains("try 1024x1024"));
+    let Ok(resolved) = resolve_throwaway_request(&context, &body).await else {
+      panic!("throwaway size rejected");
+    };
+    assert!(resolved.warnings.is_empty());
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn long_prompts_are_passed_in_files() {
+    let dir = models_dir();
+    let binary =
+      fake_binary(&dir, "echo '--prompt-file --negative-prompt-file'\n");
+    let context = fake_context(&binary, &dir, "");
+
+    let body = request(serde_json::json!({ "negative_prompt": "blurry" }));
+    let resolved = resolve_request(&context, &body).await.ok().unwrap();
+    assert!(resolved.prompt_file.is_none());
+    assert!(resolved.negative_prompt_file.is_none());
+
+    // Together the prompts fit no command line, though each would.
+    let prompt = "a cat, ".repeat(MAX_COMMAND_LEN / 14);
+    let body = request(serde_json::json!({
+      "prompt": prompt,
+      "negative_prompt": prompt,
+    }));
+    let resolved = resolve_request(&context, &body).await.ok().unwrap();
+    let prompt_file = resolved.prompt_file.as_ref().unwrap().path().to_string();
+    let negative_file = resolved
+      .negative_prompt_file
+      .as_ref()
+      .unwrap()
+      .path()
+      .to_string();
+    assert_eq!(std::fs::read_to_string(&prompt_file).unwrap(), prompt);
+    assert_eq!(std::fs::read_to_string(&negative_file).unwrap(), prompt);
+    let cmd =
+      build_command(&context, &body, &resolved, 0, "/tmp/out.png").unwrap();
+    let args: Vec<_> = cmd.as_std().get_args().collect();
+    assert!(args
+      .windows(2)
+      .any(|w| w == ["--prompt-file", &prompt_file]));
+    assert!(args
+      .windows(2)
+      .any(|w| w == ["--negative-prompt-file", &negative_file]));
+    assert!(command_len(&cmd) < MAX_COMMAND_LEN);
+
+    // A single prompt leaving no room for the rest of the command moves too.
+    let prompt = "a".repeat(MAX_COMMAND_LEN - COMMAND_LEN_MARGIN);
+    let body = request(serde_json::json!({ "prompt": prompt }));
+    let single = resolve_request(&context, &body).await.ok().unwrap();
+    assert!(single.prompt_file.is_some());
+    assert!(single.negative_prompt_file.is_none());
+    drop(resolved);
+    assert!(!std::path::Path::new(&prompt_file).exists());
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[cfg(target_os = "linux")]
+  #[tokio::test]
+  async fn timed_out_generations_are_killed_and_reaped() {
+    let dir = models_dir();
+    let pid_file = dir.join("pid");
+    let binary = fake_binary(
+      &dir,
+      &format!("echo $$ > {}\nexec sleep 30\n", pid_file.display()),
+    );
+    let context = fake_context(&binary, &dir, "timeout_secs = 1");
+
+    let started = Instant::now();
+    let output = dir.join("out.png");
+    let Err(error) =
+      run_binary(&context, Command::new(&binary), output.to_str().unwrap())
+        .await
+    else {
+      panic!("generation finished");
+    };
+    assert_eq!(error.status_code(), StatusCode::GATEWAY_TIMEOUT);
+    assert_eq!(error.error_type(), "timeout");
+    assert!(started.elapsed() < Duration::from_secs(10));
+
+    // A killed child left unreaped stays in /proc as a zombie.
+    let pid = std::fs::read_to_string(&pid_file).unwrap();
+    let proc = std::path::PathBuf::from(format!("/proc/{}", pid.trim()));
+    for _ in 0..50 {
+      if !proc.exists() {
+        break;
+      }
+      tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(!proc.exists(), "the binary was not killed and reaped");
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[cfg(target_os = "linux")]
+  #[tokio::test]
+  async fn timed_out_and_dropped_generations_leave_no_zombies() {
+    let dir = models_dir();
+    let pids = dir.join("pids");
+    let binary = fake_binary(
+      &dir,
+      &format!("echo $$ >> {}\nexec sleep 30\n", pids.display()),
+    );
+    let context = fake_context(&binary, &dir, "timeout_secs = 1");
+    let output = dir.join("out.png");
+    let run =
+      || run_binary(&context, Command::new(&binary), output.to_str().unwrap());
+
+    // Half time out, the other half are dropped mid-run like cancelled or
+    // disconnected requests.
+    let timed_out = futures_util::future::join_all((0..20).map(|_| run()));
+    let dropped = futures_util::future::join_all(
+      (0..20).map(|_| tokio::time::timeout(Duration::from_millis(300), run())),
+    );
+    let (timed_out, dropped) = tokio::join!(timed_out, dropped);
+    assert!(timed_out.iter().all(Result::is_err));
+    assert!(dropped.iter().all(Result::is_err));
+
+    // Children of this process still in /proc are running or zombies.
+    let leaked = || {
+      std::fs::read_to_string(&pids)
+        .unwrap()
+        .lines()
+        .filter(|pid| {
+          std::fs::read_to_string(format!("/proc/{}/stat", pid))
+            .ok()
+            .and_then(|stat| {
+              let ppid = stat.rsplit_once(") ")?.1.split(' ').nth(1)?;
+              ppid.parse::<u32>().ok()
+            })
+            == Some(std::process::id())
+        })
+        .count()
+    };
+    assert!(std::fs::read_to_string(&pids).unwrap().lines().count() >= 20);
+    for _ in 0..50 {
+      if leaked() == 0 {
+        break;
+      }
+      tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(leaked(), 0, "children were left running or unreaped");
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[cfg(unix)]
+  #[actix_web::test]
+  async fn cancelled_generations_leave_no_files() {
+    let dir = models_dir();
+    // The first image is written, the second never finishes.
+    let binary = image_binary(
+      &dir,
+      r#"
+        [ -e first ] && touch second && exec sleep 30
+        touch first
+      "#,
+    );
+    let context = actix_web::web::Data::new(fake_context(&binary, &dir, ""));
+    actix_web::rt::spawn(crate::queue::run_queue_worker(context.queue.clone()));
+    let body = request(serde_json::json!({ "n": 2, "response_format": "url" }));
+    let mut resolved = resolved(&body);
+    resolved.binary = binary.display().to_string();
+
+    let cancel = async {
+      while !dir.join("second").exists() {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+      }
+      let id = context.cancellations.lock().unwrap().keys().next().cloned();
+      let req = actix_web::test::TestRequest::default()
+        .insert_header(("Authorization", "Bearer t"))
+        .to_http_request();
+      let path = actix_web::web::Path::from(id.unwrap());
+      crate::handlers::cancel_generation(req, path, context.clone()).await
+    };
+    let (generated, cancelled) = tokio::join!(
+      generate_images(
+        "http://localhost",
+        &context,
+        "t",
+        &body,
+        &resolved,
+        || {}
+      ),
+      cancel,
+    );
+    assert_eq!(cancelled.unwrap().status(), StatusCode::OK);
+    let Err(error) = generated else {
+      panic!("generation finished");
+    };
+    assert_eq!(error.error_type(), "cancelled");
+    let leftovers: Vec<_> = std::fs::read_dir(&dir)
+      .unwrap()
+      .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+      .filter(|name| name.starts_with(OUTPUT_PREFIX))
+      .collect();
+    assert!(leftovers.is_empty(), "left {:?}", leftovers);
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn too_long_message_names_the_missing_flags() {
+    let mut cmd = Command::new("/opt/sd");
+    cmd.args(["--prompt-file", "/tmp/p.txt", "-n", "blurry"]);
+    let message = too_long_message(&cmd);
+    assert!(message.contains("does not support --negative-prompt-file,"));
+    let mut cmd = Command::new("/opt/sd");
+    cmd.args(["--prompt-file", "/tmp/p.txt"]);
+    assert!(too_long_message(&cmd).contains("shorten extra_args"));
+  }
+
+  #[test]
+  fn metadata_describes_the_prompt_as_run() {
+    let context = Context::for_tests(CONFIG);
+    let body = request(serde_json::json!({ "include_metadata": true }));
+    let mut resolved = resolved(&body);
+    resolved.prompt = format!("a cat <lora:{}:0.8>", "detail".repeat(100));
+    let metadata = generation_metadata(
+      &context,
+      &body,
+      &resolved,
+      Some(1),
+      Duration::ZERO,
+      Vec::new(),
+    )
+    .unwrap();
+    assert!(!metadata.prompt_truncated);
+    resolved.prompt = "a cat, ".repeat(40);
+    let metadata = generation_metadata(
+      &context,
+      &body,
+      &resolved,
+      Some(1),
+      Duration::ZERO,
+      Vec::new(),
+    )
+    .unwrap();
+    assert!(metadata.prompt_truncated);
+  }
+
+  #[tokio::test]
+  async fn only_allowed_models_are_found() {
+    let dir = models_dir();
+    std::fs::write(dir.join("bar.gguf"), b"GGUF\x03\0\0\0\0").unwrap();
+    let context = |extra: &str| {
+      Context::for_tests(&format!(
+        "{}models_dir = \"{}\"\n{}",
+        CONFIG.replace("models_dir = \"/models\"", ""),
+        dir.display(),
+        extra
+      ))
+    };
+
+    let allowlist = context("allowed_models = \"foo\"");
+    let path = find_model(&allowlist, "foo").await.unwrap();
+    assert!(path.ends_with("/foo.gguf"));
+    let error = find_model(&allowlist, "bar").await.unwrap_err();
+    assert_eq!(error.status_code(), StatusCode::FORBIDDEN);
+    assert_eq!(error.error_type(), "permission_error");
+    assert_eq!(error.message(), "model 'bar' is not allowed");
+
+    assert!(find_model(&context(""), "bar").await.is_ok());
+  }
+
+  #[test]
+  fn image_info_reads_the_header() {
+    let mut png = std::io::Cursor::new(Vec::new());
+    image::DynamicImage::new_rgba8(48, 32)
+      .write_to(&mut png, image::ImageFormat::Png)
+      .unwrap();
+    let png = png.into_inner();
+    let info = image_info(&png).unwrap();
+    assert_eq!((info.width, info.height), (48, 32));
+    assert_eq!(info.bytes, png.len());
+    assert_eq!(info.color_type, "rgba8");
+
+    assert!(image_info(b"not an image").is_none());
+  }
+}
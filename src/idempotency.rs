@@ -0,0 +1,205 @@
+//! `Idempotency-Key` support, so clients can retry a POST without running
+//! its generation twice.
+
+use crate::auth::bearer_token;
+use crate::config::Context;
+use crate::error::ApiError;
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::http::{Method, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::web::{self, Bytes};
+use actix_web::HttpResponse;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::watch;
+
+const IDEMPOTENCY_KEY_HEADER: HeaderName =
+  HeaderName::from_static("idempotency-key");
+
+/// Set on responses replayed for a repeated key.
+const REPLAYED_HEADER: HeaderName =
+  HeaderName::from_static("idempotent-replayed");
+
+/// A response kept to answer retries of its request.
+pub struct StoredResponse {
+  status: StatusCode,
+  headers: HeaderMap,
+  body: Bytes,
+  stored_at: Instant,
+}
+
+/// A request by key, whose response is sent once complete. The sender is
+/// dropped without a response when the request fails transiently or is
+/// abandoned, so a retry runs it again.
+pub type IdempotencyEntry = watch::Receiver<Option<Arc<StoredResponse>>>;
+
+/// Answers a POST repeating the `Idempotency-Key` of an earlier one with the
+/// earlier response, waiting for it if still running. Keys are scoped to
+/// the bearer token and path. Streamed responses, rate limits and server
+/// errors are not kept.
+pub async fn idempotency(
+  req: ServiceRequest,
+  next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+  let context = req.app_data::<web::Data<Context>>().cloned();
+  let (Some(context), Some(key)) =
+    (context, req.headers().get(IDEMPOTENCY_KEY_HEADER).cloned())
+  else {
+    return Ok(next.call(req).await?.map_into_boxed_body());
+  };
+  let Some(ttl) = context
+    .idempotency_ttl
+    .filter(|_| req.method() == Method::POST)
+  else {
+    return Ok(next.call(req).await?.map_into_boxed_body());
+  };
+  let key = match key.to_str() {
+    Ok(key) if (1..=255).contains(&key.len()) => key,
+    _ => {
+      return Err(
+        ApiError::bad_request(
+          "Idempotency-Key must be 1 to 255 visible ASCII characters",
+        )
+        .into(),
+      )
+    }
+  };
+  let scoped_key = format!(
+    "{}\0{}\0{}",
+    bearer_token(req.request()).unwrap_or_default(),
+    req.path(),
+    key
+  );
+
+  let sender = {
+    let mut entries = context.idempotency.lock().unwrap();
+    entries.retain(|_, entry| {
+      let stored = entry.borrow();
+      match stored.as_ref() {
+        Some(stored) => stored.stored_at.elapsed() < ttl,
+        None => entry.has_changed().is_ok(),
+      }
+    });
+    match entries.get(&scoped_key) {
+      Some(entry) => Err(entry.clone()),
+      None => {
+        let (sender, receiver) = watch::channel(None);
+        entries.insert(scoped_key.clone(), receiver);
+        Ok(sender)
+      }
+    }
+  };
+  let sender = match sender {
+    Ok(sender) => sender,
+    Err(mut entry) => {
+      let stored =
+        match entry.wait_for(Option::is_some).await {
+          Ok(stored) => stored.clone().unwrap(),
+          Err(_) => return Err(
+            ApiError::new(
+              StatusCode::CONFLICT,
+              "The first request with this Idempotency-Key did not complete, \
+               retry it",
+              "invalid_request_error",
+            )
+            .into(),
+          ),
+        };
+      tracing::info!(key = %key, "replaying idempotent response");
+      let mut response = HttpResponse::build(stored.status);
+      for (name, value) in &stored.headers {
+        response.insert_header((name.clone(), value.clone()));
+      }
+      response
+        .insert_header((REPLAYED_HEADER, HeaderValue::from_static("true")));
+      return Ok(req.into_response(response.body(stored.body.clone())));
+    }
+  };
+
+  let result = next.call(req).await;
+  let response = match result {
+    Ok(response) if is_kept(&response) => response,
+    other => {
+      context.idempotency.lock().unwrap().remove(&scoped_key);
+      drop(sender);
+      return Ok(other?.map_into_boxed_body());
+    }
+  };
+  let (req, response) = response.into_parts();
+  let (response, body) = response.into_parts();
+  let body = actix_web::body::to_bytes(body)
+    .await
+    .map_err(|e| ApiError::server_error(e.into().to_string()))?;
+  let _ = sender.send(Some(Arc::new(StoredResponse {
+    status: response.status(),
+    headers: response.headers().clone(),
+    body: body.clone(),
+    stored_at: Instant::now(),
+  })));
+  Ok(ServiceResponse::new(
+    req,
+    response.set_body(BoxBody::new(body)),
+  ))
+}
+
+/// Whether a response can be replayed: complete, and not an error a retry
+/// could get past.
+fn is_kept(response: &ServiceResponse<impl MessageBody>) -> bool {
+  let status = response.status();
+  let streamed = response
+    .headers()
+    .get(header::CONTENT_TYPE)
+    .is_some_and(|value| value.as_bytes().starts_with(b"text/event-stream"));
+  !streamed
+    && status != StatusCode::TOO_MANY_REQUESTS
+    && !status.is_server_error()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+  use super::*;
+  use crate::handlers::generate_image;
+  use crate::queue::run_queue_worker;
+  use crate::testing::{fake_context, image_binary, models_dir};
+  use actix_web::test::{call_service, init_service, read_body, TestRequest};
+  use actix_web::{middleware, App};
+
+  #[actix_web::test]
+  async fn concurrent_requests_with_a_key_run_once() {
+    let dir = models_dir();
+    let binary = image_binary(&dir, "echo run >> runs; sleep 0.3");
+    let context = fake_context(&binary, &dir, "max_concurrency = 2");
+    for _ in 0..2 {
+      actix_web::rt::spawn(run_queue_worker(context.queue.clone()));
+    }
+    let app = init_service(
+      App::new()
+        .app_data(web::Data::new(context))
+        .wrap(middleware::from_fn(idempotency))
+        .route("/v1/images/generations", web::post().to(generate_image)),
+    )
+    .await;
+    let request = || {
+      TestRequest::post()
+        .uri("/v1/images/generations")
+        .insert_header(("Authorization", "Bearer t"))
+        .insert_header((IDEMPOTENCY_KEY_HEADER, "k"))
+        .set_json(serde_json::json!({ "prompt": "a cat", "model": "foo" }))
+        .to_request()
+    };
+
+    let (first, second) = tokio::join!(
+      call_service(&app, request()),
+      call_service(&app, request()),
+    );
+    assert_eq!(first.status(), StatusCode::OK);
+    assert_eq!(second.status(), StatusCode::OK);
+    assert!(!first.headers().contains_key(REPLAYED_HEADER));
+    assert_eq!(second.headers().get(REPLAYED_HEADER).unwrap(), "true");
+    assert_eq!(read_body(first).await, read_body(second).await);
+    let runs = std::fs::read_to_string(dir.join("runs")).unwrap();
+    assert_eq!(runs.lines().count(), 1);
+  }
+}
@@ -0,0 +1,376 @@
+//! Background jobs, webhooks and cancellation of running generations.
+
+use crate::api::ImageGenerationRequest;
+use crate::auth::bearer_token;
+use crate::config::Context;
+use crate::error::ApiError;
+use crate::generation::{generate_registered_images, ResolvedRequest};
+use crate::handlers::base_url;
+use crate::REQUEST_ID;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+use tracing::Instrument;
+
+/// Runs the generation in the background as a job, posting the outcome to
+/// `webhook_url` if the request has one, and answers with the job ID.
+pub fn start_job(
+  req: &HttpRequest,
+  context: &web::Data<Context>,
+  body: ImageGenerationRequest,
+  resolved: ResolvedRequest,
+) -> HttpResponse {
+  // Registered before the job is listed as queued, for
+  // `cancel_queued_jobs` to find it.
+  let token = bearer_token(req).unwrap_or_default();
+  let cancellation = Cancellation::register(context, token);
+  let id = cancellation.id.clone();
+  let created = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap()
+    .as_secs();
+  context.jobs.lock().unwrap().insert(
+    id.clone(),
+    Job {
+      status: JobStatus::Queued,
+      created,
+      finished: None,
+      result: None,
+      token: token.to_string(),
+      user: body.user.clone(),
+    },
+  );
+
+  let base_url = base_url(req, context);
+  let job = {
+    let id = id.clone();
+    let context = context.clone();
+    async move {
+      let set_status = |status| {
+        if let Some(job) = context.jobs.lock().unwrap().get_mut(&id) {
+          job.status = status;
+        }
+      };
+      let on_start = || set_status(JobStatus::Running);
+      let response = generate_registered_images(
+        &base_url,
+        &context,
+        &body,
+        &resolved,
+        cancellation,
+        on_start,
+      )
+      .await
+      .unwrap_or_else(|e| e.error_response());
+      let status = if response.status().is_success() {
+        JobStatus::Succeeded
+      } else {
+        JobStatus::Failed
+      };
+      let result = actix_web::body::to_bytes(response.into_body())
+        .await
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok());
+      let payload = context.jobs.lock().unwrap().get_mut(&id).map(|job| {
+        job.status = status;
+        job.finished = Some(Instant::now());
+        job.result = result;
+        job_json(&id, job)
+      });
+      tracing::info!(status = ?status, "job finished");
+      if let (Some(webhook_url), Some(payload)) = (&body.webhook_url, payload) {
+        send_webhook(&context, webhook_url, &payload).await;
+      }
+    }
+  };
+  // Keep the request's ID and span for the job's logs.
+  actix_web::rt::spawn(
+    REQUEST_ID.scope(id.clone(), job.instrument(tracing::Span::current())),
+  );
+
+  HttpResponse::Accepted().json(serde_json::json!({
+    "id": id,
+    "object": "job",
+    "status": JobStatus::Queued,
+    "created": created,
+  }))
+}
+
+pub struct Job {
+  status: JobStatus,
+  created: u64,
+  finished: Option<Instant>,
+  /// Response body of the finished generation.
+  result: Option<serde_json::Value>,
+  /// Bearer token of the request that started the job.
+  token: String,
+  user: Option<String>,
+}
+
+impl Job {
+  /// Whether the job was started with the bearer `token`, which alone may
+  /// read it.
+  pub fn started_by(&self, token: &str) -> bool {
+    self.token == token
+  }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum JobStatus {
+  Queued,
+  Running,
+  Succeeded,
+  Failed,
+}
+
+/// The job as returned by `GET /v1/jobs/{id}` and posted to webhooks.
+pub fn job_json(id: &str, job: &Job) -> serde_json::Value {
+  let mut response = serde_json::json!({
+    "id": id,
+    "object": "job",
+    "status": job.status,
+    "created": job.created,
+  });
+  match (job.status, &job.result) {
+    (JobStatus::Succeeded, Some(result)) => {
+      response["result"] = result.clone();
+    }
+    (JobStatus::Failed, Some(result)) => {
+      response["error"] = result["error"].clone();
+    }
+    _ => {}
+  }
+  response
+}
+
+/// Delays between attempts to deliver a webhook.
+const WEBHOOK_RETRY_DELAYS: &[Duration] = &[
+  Duration::from_secs(1),
+  Duration::from_secs(5),
+  Duration::from_secs(30),
+];
+
+/// Posts a finished job to its webhook, retrying failed deliveries.
+async fn send_webhook(
+  context: &Context,
+  webhook_url: &str,
+  payload: &serde_json::Value,
+) {
+  let mut delays = WEBHOOK_RETRY_DELAYS.iter();
+  loop {
+    let result = context
+      .http
+      .post(webhook_url)
+      .json(payload)
+      .timeout(Duration::from_secs(30))
+      .send()
+      .await
+      .and_then(|response| response.error_for_status());
+    match result {
+      Ok(_) => {
+        tracing::info!(webhook_url, "webhook delivered");
+        return;
+      }
+      Err(e) => match delays.next() {
+        Some(delay) => {
+          tracing::warn!(error = %e, retry_in = ?delay, "webhook failed");
+          tokio::time::sleep(*delay).await;
+        }
+        None => {
+          tracing::error!(error = %e, webhook_url, "giving up on webhook");
+          return;
+        }
+      },
+    }
+  }
+}
+
+/// Cancels the jobs started with `token` that are still waiting for a
+/// generation slot, only those of `user` when set, and returns their IDs.
+/// Running jobs are left alone. They end as failed, like jobs cancelled by
+/// ID.
+pub fn cancel_queued_jobs(
+  context: &Context,
+  token: &str,
+  user: Option<&str>,
+) -> Vec<String> {
+  // Holding the jobs lock keeps queued jobs from starting meanwhile.
+  let jobs = context.jobs.lock().unwrap();
+  let mut cancellations = context.cancellations.lock().unwrap();
+  jobs
+    .iter()
+    .filter(|(_, job)| {
+      matches!(job.status, JobStatus::Queued)
+        && job.token == token
+        && user.is_none_or(|user| job.user.as_deref() == Some(user))
+    })
+    .filter(|(id, _)| {
+      cancellations
+        .remove(id.as_str())
+        .is_some_and(|(_, sender)| sender.send(()).is_ok())
+    })
+    .map(|(id, _)| id.clone())
+    .collect()
+}
+
+/// Periodically forgets jobs finished more than `job_ttl` ago.
+pub async fn cleanup_finished_jobs(context: Context) {
+  let mut interval = tokio::time::interval(Duration::from_secs(60));
+  loop {
+    interval.tick().await;
+    context.jobs.lock().unwrap().retain(|_, job| {
+      job
+        .finished
+        .is_none_or(|finished| finished.elapsed() < context.job_ttl)
+    });
+  }
+}
+
+/// Running generations by ID, with the bearer token that started them.
+pub type Cancellations = HashMap<String, (String, oneshot::Sender<()>)>;
+
+/// Registers the current request's generation for cancellation by
+/// `DELETE /v1/images/generations/{id}`, until dropped.
+pub struct Cancellation {
+  /// The request ID.
+  pub id: String,
+  receiver: oneshot::Receiver<()>,
+  cancellations: Arc<Mutex<Cancellations>>,
+}
+
+impl Cancellation {
+  /// Registers the generation of a request with the bearer `token`, the
+  /// only one allowed to cancel it.
+  pub fn register(context: &Context, token: &str) -> Self {
+    let id = REQUEST_ID
+      .try_with(Clone::clone)
+      .unwrap_or_else(|_| uuid::Uuid::new_v4().to_string());
+    let (sender, receiver) = oneshot::channel();
+    context
+      .cancellations
+      .lock()
+      .unwrap()
+      .insert(id.clone(), (token.to_string(), sender));
+    Cancellation {
+      id,
+      receiver,
+      cancellations: context.cancellations.clone(),
+    }
+  }
+
+  /// Resolves once the generation is cancelled.
+  pub async fn cancelled(&mut self) {
+    if (&mut self.receiver).await.is_err() {
+      std::future::pending::<()>().await;
+    }
+  }
+}
+
+impl Drop for Cancellation {
+  fn drop(&mut self) {
+    self.cancellations.lock().unwrap().remove(&self.id);
+  }
+}
+
+pub fn cancelled_error(context: &Context) -> ApiError {
+  tracing::info!("generation cancelled");
+  context.metrics.record_failure("cancelled");
+  ApiError::new(
+    StatusCode::CONFLICT,
+    "Generation was cancelled",
+    "cancelled",
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::handlers::job_status;
+  use crate::queue::{acquire_generation_slot, run_queue_worker};
+
+  async fn start(context: &web::Data<Context>, user: &str) -> String {
+    let req = actix_web::test::TestRequest::default()
+      .insert_header(("Authorization", "Bearer t"))
+      .to_http_request();
+    let body: ImageGenerationRequest = serde_json::from_value(
+      serde_json::json!({ "prompt": "a cat", "model": "foo", "user": user }),
+    )
+    .unwrap();
+    let resolved = ResolvedRequest {
+      model: "/models/foo.gguf".to_string(),
+      model_sha256: None,
+      prompt: body.prompt.clone(),
+      negative_prompt: None,
+      extra_args: Vec::new(),
+      strength: None,
+      warnings: Vec::new(),
+      binary: "/opt/sd".to_string(),
+      refiner: None,
+      prompt_file: None,
+      negative_prompt_file: None,
+    };
+    let response = start_job(&req, context, body, resolved);
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let body = actix_web::body::to_bytes(response.into_body())
+      .await
+      .unwrap();
+    let job: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    job["id"].as_str().unwrap().to_string()
+  }
+
+  #[actix_web::test]
+  async fn cancels_jobs_queued_before_their_task_runs() {
+    let context = web::Data::new(Context::for_tests(
+      r#"
+        port = 8080
+        token = "t"
+        binary_path = "/opt/sd"
+        models_dir = "/models"
+      "#,
+    ));
+    actix_web::rt::spawn(run_queue_worker(context.queue.clone()));
+    let _slot = acquire_generation_slot(&context).await.unwrap();
+    let alice = start(&context, "alice").await;
+    let bob = start(&context, "bob").await;
+
+    // Neither job's task has run yet.
+    let cancelled = cancel_queued_jobs(&context, "t", Some("alice"));
+    assert_eq!(cancelled, [alice.as_str()]);
+    assert!(cancel_queued_jobs(&context, "other", None).is_empty());
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let jobs = context.jobs.lock().unwrap();
+    assert!(matches!(jobs[&alice].status, JobStatus::Failed));
+    assert!(matches!(jobs[&bob].status, JobStatus::Queued));
+  }
+
+  #[actix_web::test]
+  async fn jobs_are_only_shown_to_their_token() {
+    let context = web::Data::new(Context::for_tests(
+      r#"
+        port = 8080
+        token = "t,u"
+        binary_path = "/opt/sd"
+        models_dir = "/models"
+      "#,
+    ));
+    let id = start(&context, "alice").await;
+    let status = |token: &str| {
+      let req = actix_web::test::TestRequest::default()
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .to_http_request();
+      job_status(req, web::Path::from(id.clone()), context.clone())
+    };
+
+    let response = status("t").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let error = status("u").await.unwrap_err();
+    assert_eq!(error.status_code(), StatusCode::NOT_FOUND);
+    assert_eq!(error.message(), format!("job '{}' not found", id));
+  }
+}
@@ -1,258 +1,463 @@
-use actix_web::{middleware, web, App, HttpRequest, HttpResponse, HttpServer};
-use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::process::Command;
+mod api;
+mod auth;
+mod breaker;
+mod cache;
+mod config;
+mod error;
+mod files;
+mod generation;
+mod handlers;
+mod idempotency;
+mod jobs;
+mod metrics;
+mod persistent;
+mod process;
+mod queue;
+mod selftest;
+mod shutdown;
+mod storage;
+#[cfg(test)]
+mod testing;
+
+use crate::config::Context;
+use crate::error::ApiError;
+use crate::files::{
+  cleanup_expired_images, remove_old_files, INPUT_PREFIX, OUTPUT_PREFIX,
+};
+use crate::generation::binary_version;
+use crate::handlers::{
+  cancel_generation, cancel_jobs, controlnet_image, edit_image,
+  edit_image_multipart, generate_image, generate_image_async,
+  generate_image_stream, health_check, inpaint_image, job_status, list_models,
+  list_samplers, list_styles, metrics, queue_status, readiness_check,
+  reload_config, serve_image, server_config, sweep_images, upscale_image,
+  version, warmup_model,
+};
+use crate::idempotency::idempotency;
+use crate::jobs::cleanup_finished_jobs;
+use crate::queue::run_queue_worker;
+use crate::selftest::{run_self_test, SelfTestStatus};
+use crate::shutdown::{count_in_flight, stop_on_signal};
+use actix_web::body::MessageBody;
+use actix_web::dev::{Server, ServiceRequest, ServiceResponse};
+use actix_web::error::JsonPayloadError;
+use actix_web::http::{header, StatusCode};
+use actix_web::middleware::{self, Next};
+use actix_web::{guard, web, App, HttpRequest, HttpServer};
+use std::net::TcpListener;
+use tracing::Instrument;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-  let context = Context::default();
+  init_logging();
+  let mut context = match Context::load() {
+    Ok(context) => context,
+    Err(e) => {
+      eprint!("{}", e);
+      std::process::exit(1);
+    }
+  };
   let port = context.port;
-  println!("Starting stable-diffusion.cpp server on port {port}...");
-  HttpServer::new(move || {
-    App::new()
-      .app_data(web::Data::new(context.clone()))
-      .wrap(middleware::Logger::default())
-      .route("/v1/images/generations", web::post().to(generate_image))
-      .route("/health", web::get().to(health_check))
-  })
-  .bind(("0.0.0.0", port))?
-  .run()
-  .await
+  tracing::info!("Starting stable-diffusion.cpp server on port {port}...");
+  context.binary_version = binary_version(&context.binary_path).await;
+  for (backend, binary) in &context.binaries {
+    let version = binary_version(binary).await;
+    context.backend_versions.insert(backend.clone(), version);
+  }
+  tracing::info!(
+    server = env!("CARGO_PKG_VERSION"),
+    binary = context.binary_version.as_deref().unwrap_or("unknown"),
+    "versions"
+  );
+  if context.models_store.is_some() {
+    if let Err(e) = tokio::fs::create_dir_all(&context.models_dir).await {
+      tracing::error!(error = %e, "failed to create models cache directory");
+    }
+  }
+  if context.keep_outputs {
+    tracing::warn!(
+      "SD_CPP_SERVER_KEEP_OUTPUTS is set: outputs stay in the cache directory \
+       until they expire, which is meant for debugging only"
+    );
+  }
+  // Leftovers of a crash or kill, which no TempFile guard removed.
+  remove_old_files(
+    &context.cache_dir,
+    &[OUTPUT_PREFIX, INPUT_PREFIX],
+    context.stale_age,
+  )
+  .await;
+  actix_web::rt::spawn(cleanup_expired_images(context.clone()));
+  actix_web::rt::spawn(cleanup_finished_jobs(context.clone()));
+  for _ in 0..context.queue.workers {
+    actix_web::rt::spawn(run_queue_worker(context.queue.clone()));
+  }
+  if let Some(model) = context.self_test_model.clone() {
+    tracing::info!(model = %model, "running startup self-test");
+    *context.self_test.lock().unwrap() = Some(SelfTestStatus::Running);
+    actix_web::rt::spawn(run_self_test(context.clone(), model));
+  }
+  let listener = TcpListener::bind(("0.0.0.0", port))?;
+  let in_flight = context.in_flight.clone();
+  let shutdown_grace = context.shutdown_grace;
+  let server = server(context, listener)?;
+  actix_web::rt::spawn(stop_on_signal(
+    server.handle(),
+    in_flight,
+    shutdown_grace,
+  ));
+  server.await?;
+  tracing::info!("Server stopped");
+  Ok(())
 }
 
-#[derive(Clone)]
-struct Context {
-  port: u16,
-  token: String,
-  binary_path: String,
-  diffusion: bool,
-  args: Option<Vec<String>>,
-  force_scale: Option<i32>,
-  models_dir: String,
-  cache_dir: String,
+/// Serves the API on `listener` until stopped, which `shutdown` takes care
+/// of on signals.
+fn server(context: Context, listener: TcpListener) -> std::io::Result<Server> {
+  Ok(
+    HttpServer::new(move || {
+      App::new()
+        .app_data(web::Data::new(context.clone()))
+        .app_data(
+          web::JsonConfig::default()
+            .limit(context.max_body_bytes)
+            .error_handler(json_error),
+        )
+        .wrap(middleware::from_fn(idempotency))
+        .wrap(middleware::Condition::new(
+          context.cors_origins.is_some(),
+          cors(context.cors_origins.as_deref().unwrap_or_default()),
+        ))
+        .wrap(middleware::Logger::default())
+        .wrap(middleware::from_fn(request_id))
+        .wrap(middleware::from_fn(count_in_flight))
+        .route("/v1/images/generations", web::post().to(generate_image))
+        .route(
+          "/v1/images/generations/stream",
+          web::post().to(generate_image_stream),
+        )
+        .route(
+          "/v1/images/generations/async",
+          web::post().to(generate_image_async),
+        )
+        .route("/v1/jobs", web::delete().to(cancel_jobs))
+        .route("/v1/jobs/{id}", web::get().to(job_status))
+        .route(
+          "/v1/images/generations/{id}",
+          web::delete().to(cancel_generation),
+        )
+        .service(
+          web::resource("/v1/images/edits")
+            .route(
+              web::post()
+                .guard(guard::fn_guard(is_multipart))
+                .to(edit_image_multipart),
+            )
+            .route(web::post().to(edit_image)),
+        )
+        .route("/v1/images/inpaint", web::post().to(inpaint_image))
+        .route("/v1/images/controlnet", web::post().to(controlnet_image))
+        .route("/v1/images/sweep", web::post().to(sweep_images))
+        .route("/v1/images/upscale", web::post().to(upscale_image))
+        .route("/v1/models", web::get().to(list_models))
+        .route("/v1/models/{model}/warmup", web::post().to(warmup_model))
+        .route("/v1/samplers", web::get().to(list_samplers))
+        .route("/v1/styles", web::get().to(list_styles))
+        .route("/v1/queue", web::get().to(queue_status))
+        .route("/v1/config", web::get().to(server_config))
+        .route("/v1/admin/reload", web::post().to(reload_config))
+        .route("/images/{filename}", web::get().to(serve_image))
+        .route("/metrics", web::get().to(metrics))
+        .route("/version", web::get().to(version))
+        .route("/health", web::get().to(health_check))
+        .route("/health/live", web::get().to(health_check))
+        .route("/health/ready", web::get().to(readiness_check))
+    })
+    .listen(listener)?
+    .disable_signals()
+    .run(),
+  )
 }
 
-impl Default for Context {
-  fn default() -> Self {
-    Context {
-      port: std::env::var("SD_CPP_SERVER_PORT")
-        .expect("SD_CPP_SERVER_PORT environment variable not set")
-        .parse::<u16>()
-        .expect("SD_CPP_SERVER_PORT must be a valid port number"),
-      token: std::env::var("SD_CPP_SERVER_TOKEN")
-        .expect("SD_CPP_SERVER_TOKEN environment variable not set"),
-
-      binary_path: std::env::var("SD_CPP_SERVER_BINARY")
-        .expect("SD_CPP_SERVER_BINARY environment variable not set"),
-
-      diffusion: std::env::var("SD_CPP_SERVER_DIFFUSION")
-        .unwrap_or_else(|_| "0".to_string())
-        == "1",
-
-      args: std::env::var("SD_CPP_SERVER_ARGS")
-        .ok()
-        .map(|s| s.split_whitespace().map(|s| s.to_string()).collect()),
-      force_scale: std::env::var("SD_CPP_SERVER_FORCE_SCALE")
-        .ok()
-        .and_then(|s| s.parse::<i32>().ok()),
-
-      models_dir: std::env::var("SD_CPP_SERVER_MODELS")
-        .expect("SD_CPP_SERVER_MODELS environment variable not set"),
-      cache_dir: std::env::var("SD_CPP_SERVER_CACHE")
-        .unwrap_or_else(|_| "/tmp".to_string()),
-    }
+/// Logs as JSON lines when `SD_CPP_SERVER_LOG_FORMAT=json`, plain text
+/// otherwise. The level is taken from `RUST_LOG`, defaulting to `info`.
+fn init_logging() {
+  let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+    .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
+  let builder = tracing_subscriber::fmt().with_env_filter(filter);
+  match std::env::var("SD_CPP_SERVER_LOG_FORMAT").as_deref() {
+    Ok("json") => builder.json().init(),
+    _ => builder.init(),
   }
 }
 
-async fn generate_image(
-  req: HttpRequest,
-  body: web::Json<ImageGenerationRequest>,
-  context: web::Data<Context>,
-) -> HttpResponse {
-  println!("[REQUEST] {:?}", body);
+tokio::task_local! {
+  /// ID of the request being handled, set by the `request_id` middleware.
+  pub static REQUEST_ID: String;
+}
 
-  if let Err(response) = verify_bearer_token(&req, &context.token) {
-    return response;
+/// CORS policy allowing browser clients on `origins`, or on any origin if
+/// they include `*`.
+fn cors(origins: &[String]) -> actix_cors::Cors {
+  let mut cors = actix_cors::Cors::default()
+    .allowed_methods(["GET", "POST", "DELETE", "OPTIONS"])
+    .allowed_headers([
+      header::AUTHORIZATION,
+      header::CONTENT_TYPE,
+      REQUEST_ID_HEADER,
+      header::HeaderName::from_static("idempotency-key"),
+    ])
+    .expose_headers([
+      "Retry-After",
+      "X-Cache",
+      "X-Request-Id",
+      "Idempotent-Replayed",
+    ])
+    .max_age(3600);
+  for origin in origins {
+    cors = if origin == "*" {
+      cors.allow_any_origin()
+    } else {
+      cors.allowed_origin(origin)
+    };
   }
+  cors
+}
 
-  let timestamp = SystemTime::now()
-    .duration_since(UNIX_EPOCH)
-    .unwrap()
-    .as_secs();
+fn is_multipart(ctx: &guard::GuardContext) -> bool {
+  ctx
+    .header::<header::ContentType>()
+    .is_some_and(|content_type| {
+      content_type.0.essence_str() == "multipart/form-data"
+    })
+}
 
-  let output_path =
-    format!("{}/sd_output_{}.png", context.cache_dir, timestamp);
+/// Answers malformed, oversized or non-JSON bodies with the usual error body
+/// instead of actix's plain text.
+fn json_error(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+  let response = match &err {
+    JsonPayloadError::Overflow { limit }
+    | JsonPayloadError::OverflowKnownLength { limit, .. } => ApiError::new(
+      StatusCode::PAYLOAD_TOO_LARGE,
+      format!("Request body exceeds the limit of {} bytes", limit),
+      "invalid_request_error",
+    ),
+    JsonPayloadError::ContentType => ApiError::new(
+      StatusCode::UNSUPPORTED_MEDIA_TYPE,
+      "Content-Type must be application/json",
+      "invalid_request_error",
+    ),
+    _ => ApiError::bad_request(format!("Invalid JSON body: {}", err)),
+  };
+  response.into()
+}
 
-  let mut cmd = Command::new(&context.binary_path);
-  if let Some(args) = &context.args {
-    for arg in args {
-      cmd.arg(arg);
-    }
+/// Tags every request with an ID, attached to its log span, to any error
+/// response built while handling it and to the `X-Request-Id` response
+/// header. A client's own `X-Request-Id` is kept when it is safe to echo and
+/// no job or running generation already goes by it.
+async fn request_id(
+  req: ServiceRequest,
+  next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+  let incoming = req
+    .headers()
+    .get(REQUEST_ID_HEADER)
+    .and_then(|value| value.to_str().ok())
+    .filter(|id| is_valid_request_id(id))
+    .filter(|id| {
+      req.app_data::<web::Data<Context>>().is_none_or(|context| {
+        !context.jobs.lock().unwrap().contains_key(*id)
+          && !context.cancellations.lock().unwrap().contains_key(*id)
+      })
+    })
+    .map(str::to_string);
+  let id = incoming.unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+  let span = tracing::info_span!(
+    "request",
+    request_id = %id,
+    method = %req.method(),
+    path = %req.path(),
+  );
+  let header_value = header::HeaderValue::from_str(&id);
+  let mut response = REQUEST_ID
+    .scope(id, next.call(req).instrument(span))
+    .await?;
+  if let Ok(value) = header_value {
+    response.headers_mut().insert(REQUEST_ID_HEADER, value);
   }
+  Ok(response)
+}
 
-  let model = format!("{}/{}.gguf", context.models_dir, body.model);
-
-  if context.diffusion {
-    cmd.arg("--diffusion-model").arg(&model);
-  } else {
-    cmd.arg("-m").arg(&model);
-  }
+const REQUEST_ID_HEADER: header::HeaderName =
+  header::HeaderName::from_static("x-request-id");
 
-  cmd.arg("-p").arg(&body.prompt);
-  cmd.arg("-o").arg(&output_path);
-  cmd.arg("--steps").arg(body.steps.to_string());
+/// Incoming IDs are limited to characters that cannot break a header or a
+/// log line, such as the UUIDs and hex IDs proxies generate.
+fn is_valid_request_id(id: &str) -> bool {
+  (1..=128).contains(&id.len())
+    && id
+      .bytes()
+      .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
 
-  if let Some(force_scale) = context.force_scale {
-    cmd.arg("--cfg-scale").arg(force_scale.to_string());
-  } else {
-    cmd.arg("--cfg-scale").arg(body.cfg_scale.to_string());
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::shutdown::drain;
+  #[cfg(unix)]
+  use crate::testing::{fake_context, image_binary, models_dir};
+  use actix_web::dev::ServerHandle;
+  use actix_web::rt::task::JoinHandle;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::Arc;
+  use std::time::{Duration, Instant};
+
+  const CONFIG: &str = r#"
+    port = 8080
+    token = "t"
+    binary_path = "/opt/sd"
+    models_dir = "/models"
+  "#;
+
+  /// Serves `context` on a free local port, returning the server and its URL.
+  fn serve(context: Context) -> (Server, String) {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    (server(context, listener).unwrap(), url)
   }
 
-  if body.seed >= 0 {
-    cmd.arg("--seed").arg(body.seed.to_string());
+  /// Sends a generation request built by `request` to a server running
+  /// with `extra` settings, returning its status and error.
+  async fn rejected_generation(
+    extra: &str,
+    request: impl FnOnce(reqwest::RequestBuilder) -> reqwest::RequestBuilder,
+  ) -> (reqwest::StatusCode, serde_json::Value) {
+    let (server, url) =
+      serve(Context::for_tests(&format!("{}{}", CONFIG, extra)));
+    let handle = server.handle();
+    actix_web::rt::spawn(server);
+    let response = request(
+      reqwest::Client::new()
+        .post(format!("{}/v1/images/generations", url))
+        .bearer_auth("t"),
+    )
+    .send()
+    .await
+    .unwrap();
+    let status = response.status();
+    let body: serde_json::Value = response.json().await.unwrap();
+    handle.stop(false).await;
+    (status, body["error"].clone())
   }
 
-  if let Some(neg_prompt) = &body.negative_prompt {
-    cmd.arg("-n").arg(neg_prompt);
+  #[test]
+  fn request_ids_are_short_and_plain() {
+    assert!(is_valid_request_id("3f2c-11_a.b"));
+    assert!(is_valid_request_id(&"a".repeat(128)));
+    assert!(!is_valid_request_id(""));
+    assert!(!is_valid_request_id(&"a".repeat(129)));
+    assert!(!is_valid_request_id("a b"));
+    assert!(!is_valid_request_id("a\r\nSet-Cookie: x"));
+    assert!(!is_valid_request_id("../jobs"));
   }
 
-  let size_parts: Vec<&str> = body.size.split('x').collect();
-  if size_parts.len() == 2 {
-    cmd.arg("-W").arg(size_parts[0]);
-    cmd.arg("-H").arg(size_parts[1]);
+  /// Starts a generation on a server running `binary` in `dir`, returning
+  /// the server, its pending response and its in-flight count once the
+  /// binary runs.
+  #[cfg(unix)]
+  async fn start_generation(
+    binary: &std::path::Path,
+    dir: &std::path::Path,
+  ) -> (
+    ServerHandle,
+    JoinHandle<Result<reqwest::Response, reqwest::Error>>,
+    Arc<AtomicUsize>,
+  ) {
+    let context = fake_context(binary, dir, "");
+    actix_web::rt::spawn(run_queue_worker(context.queue.clone()));
+    let in_flight = context.in_flight.clone();
+    let (server, url) = serve(context);
+    let handle = server.handle();
+    actix_web::rt::spawn(server);
+    let response = actix_web::rt::spawn(
+      reqwest::Client::new()
+        .post(format!("{}/v1/images/generations", url))
+        .bearer_auth("t")
+        .json(&serde_json::json!({ "prompt": "a cat", "model": "foo" }))
+        .send(),
+    );
+    while !dir.join("started").exists() {
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    (handle, response, in_flight)
   }
 
-  println!("[COMMAND] {:?}", cmd);
+  #[cfg(unix)]
+  #[actix_web::test]
+  async fn in_flight_generations_finish_on_shutdown() {
+    let dir = models_dir();
+    let binary = image_binary(&dir, "touch started; sleep 1");
+    let (server, response, in_flight) = start_generation(&binary, &dir).await;
+    drain(&server, &in_flight, Duration::from_secs(30)).await;
+
+    let response = response.await.unwrap().unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["data"].as_array().unwrap().len(), 1);
+    assert_eq!(in_flight.load(Ordering::SeqCst), 0);
+  }
 
-  match cmd.output().await {
-    Ok(output) => {
-      if output.status.success() {
-        println!("[OUTPUT] {:?}", output);
-        match tokio::fs::read(&output_path).await {
-          Ok(image_data) => {
-            let b64_json = base64::Engine::encode(
-              &base64::engine::general_purpose::STANDARD,
-              &image_data,
-            );
-            let _ = tokio::fs::remove_file(&output_path).await;
-            HttpResponse::Ok().json(ImageGenerationResponse {
-              created: timestamp,
-              data: vec![ImageData { b64_json }],
-            })
-          }
-          Err(e) => {
-            println!("[ERROR/READ] {:?}", e);
-            HttpResponse::InternalServerError().json(ErrorResponse {
-              error: ErrorDetail {
-                message: format!("Failed to read output image: {}", e),
-                error_type: "server_error".to_string(),
-              },
-            })
-          }
-        }
-      } else {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        println!("[ERROR/OUTPUT] {:?}", stderr);
-        HttpResponse::InternalServerError().json(ErrorResponse {
-          error: ErrorDetail {
-            message: format!("Image generation failed: {}", stderr),
-            error_type: "server_error".to_string(),
-          },
-        })
-      }
-    }
-    Err(e) => {
-      println!("[ERROR/EXECUTE] {:?}", e);
-      HttpResponse::InternalServerError().json(ErrorResponse {
-        error: ErrorDetail {
-          message: format!("Failed to execute sd command: {}", e),
-          error_type: "server_error".to_string(),
-        },
+  #[cfg(unix)]
+  #[actix_web::test]
+  async fn generations_past_the_grace_period_are_dropped() {
+    let dir = models_dir();
+    let binary = image_binary(&dir, "touch started; exec sleep 30");
+    let (server, response, in_flight) = start_generation(&binary, &dir).await;
+    let started = Instant::now();
+    drain(&server, &in_flight, Duration::from_millis(200)).await;
+
+    assert!(response.await.unwrap().is_err());
+    assert!(started.elapsed() < Duration::from_secs(5));
+    // Dropping the request removed its temp files.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let outputs = std::fs::read_dir(&dir)
+      .unwrap()
+      .filter(|entry| {
+        let name = entry.as_ref().unwrap().file_name();
+        name.to_string_lossy().starts_with(OUTPUT_PREFIX)
       })
-    }
+      .count();
+    assert_eq!(outputs, 0);
   }
-}
 
-#[derive(Debug, Deserialize)]
-struct ImageGenerationRequest {
-  prompt: String,
-  model: String,
-  #[serde(default = "default_size")]
-  size: String,
-  #[serde(default)]
-  negative_prompt: Option<String>,
-  #[serde(default = "default_steps")]
-  steps: u32,
-  #[serde(default = "default_cfg_scale")]
-  cfg_scale: f32,
-  #[serde(default = "default_seed")]
-  seed: i32,
-}
-
-fn default_size() -> String {
-  "512x512".to_string()
-}
-
-fn default_steps() -> u32 {
-  20
-}
-
-fn default_cfg_scale() -> f32 {
-  7.0
-}
-
-fn default_seed() -> i32 {
-  -1
-}
-
-#[derive(Debug, Serialize)]
-struct ImageGenerationResponse {
-  created: u64,
-  data: Vec<ImageData>,
-}
-
-#[derive(Debug, Serialize)]
-struct ImageData {
-  b64_json: String,
-}
-
-#[derive(Debug, Serialize)]
-struct ErrorResponse {
-  error: ErrorDetail,
-}
-
-#[derive(Debug, Serialize)]
-struct ErrorDetail {
-  message: String,
-  #[serde(rename = "type")]
-  error_type: String,
-}
-
-fn verify_bearer_token(
-  req: &HttpRequest,
-  expected_token: &str,
-) -> Result<(), HttpResponse> {
-  if let Some(auth_header) = req.headers().get("authorization") {
-    if let Ok(auth_str) = auth_header.to_str() {
-      if auth_str.starts_with("Bearer ") && &auth_str[7..] == expected_token {
-        return Ok(());
-      }
-    }
+  #[actix_web::test]
+  async fn oversized_bodies_give_413() {
+    let (status, error) = rejected_generation("max_body_bytes = 1024", |req| {
+      req.json(&serde_json::json!({
+        "prompt": "a cat ".repeat(200),
+        "model": "foo",
+      }))
+    })
+    .await;
+    assert_eq!(status, reqwest::StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(error["type"], "invalid_request_error");
+    assert_eq!(
+      error["message"],
+      "Request body exceeds the limit of 1024 bytes"
+    );
   }
-  Err(HttpResponse::Unauthorized().json(ErrorResponse {
-    error: ErrorDetail {
-      message: "Invalid or missing authorization token".to_string(),
-      error_type: "invalid_request_error".to_string(),
-    },
-  }))
-}
 
-async fn health_check() -> HttpResponse {
-  HttpResponse::Ok().json(serde_json::json!({
-      "status": "ok",
-      "timestamp": SystemTime::now()
-          .duration_since(UNIX_EPOCH)
-          .unwrap()
-          .as_secs()
-  }))
+  #[actix_web::test]
+  async fn non_json_bodies_give_415() {
+    let (status, error) = rejected_generation("", |req| {
+      req
+        .header("Content-Type", "text/plain")
+        .body(r#"{"prompt": "a cat", "model": "foo"}"#)
+    })
+    .await;
+    assert_eq!(status, reqwest::StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert_eq!(error["type"], "invalid_request_error");
+    assert_eq!(error["message"], "Content-Type must be application/json");
+  }
 }
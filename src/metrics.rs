@@ -0,0 +1,141 @@
+//! Prometheus metrics.
+
+use crate::breaker::BreakerStatus;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Histogram bounds in seconds, spread for generations that take seconds to
+/// minutes.
+const DURATION_BUCKETS: &[f64] = &[
+  0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0, 300.0, 600.0,
+];
+
+#[derive(Default)]
+pub struct Metrics {
+  generations: AtomicU64,
+  failures: Mutex<BTreeMap<String, u64>>,
+  in_flight: AtomicUsize,
+  generation_seconds: Histogram,
+  pub queue_wait_seconds: Histogram,
+}
+
+/// Decrements the in-flight gauge when dropped.
+pub struct InFlight(Arc<Metrics>);
+
+impl Drop for InFlight {
+  fn drop(&mut self) {
+    self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+  }
+}
+
+impl Metrics {
+  pub fn in_flight(self: &Arc<Self>) -> InFlight {
+    self.in_flight.fetch_add(1, Ordering::SeqCst);
+    InFlight(self.clone())
+  }
+
+  pub fn record_generation(&self, duration: Duration) {
+    self.generations.fetch_add(1, Ordering::SeqCst);
+    self.generation_seconds.observe(duration);
+  }
+
+  pub fn record_failure(&self, error_type: &str) {
+    *self
+      .failures
+      .lock()
+      .unwrap()
+      .entry(error_type.to_string())
+      .or_default() += 1;
+  }
+
+  /// Renders the Prometheus text exposition format, with the number of
+  /// requests waiting for a generation slot and the circuit breaker state.
+  pub fn render(&self, queue_depth: usize, breaker: BreakerStatus) -> String {
+    let mut out = String::new();
+    out.push_str("# TYPE sd_generations_total counter\n");
+    out.push_str(&format!(
+      "sd_generations_total {}\n",
+      self.generations.load(Ordering::SeqCst)
+    ));
+    out.push_str("# TYPE sd_failures_total counter\n");
+    for (error_type, count) in self.failures.lock().unwrap().iter() {
+      out.push_str(&format!(
+        "sd_failures_total{{error_type=\"{}\"}} {}\n",
+        error_type, count
+      ));
+    }
+    out.push_str("# TYPE sd_in_flight_requests gauge\n");
+    out.push_str(&format!(
+      "sd_in_flight_requests {}\n",
+      self.in_flight.load(Ordering::SeqCst)
+    ));
+    out.push_str("# TYPE sd_queue_depth gauge\n");
+    out.push_str(&format!("sd_queue_depth {}\n", queue_depth));
+    out.push_str("# TYPE sd_circuit_breaker_open gauge\n");
+    out.push_str(&format!(
+      "sd_circuit_breaker_open {}\n",
+      (breaker != BreakerStatus::Closed) as u8
+    ));
+    self
+      .generation_seconds
+      .render("sd_generation_duration_seconds", &mut out);
+    self
+      .queue_wait_seconds
+      .render("sd_queue_wait_seconds", &mut out);
+    out
+  }
+}
+
+pub struct Histogram {
+  /// Cumulative count per `DURATION_BUCKETS` bound.
+  buckets: Vec<AtomicU64>,
+  count: AtomicU64,
+  sum_micros: AtomicU64,
+}
+
+impl Default for Histogram {
+  fn default() -> Self {
+    Histogram {
+      buckets: DURATION_BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+      count: AtomicU64::new(0),
+      sum_micros: AtomicU64::new(0),
+    }
+  }
+}
+
+impl Histogram {
+  pub fn observe(&self, duration: Duration) {
+    let secs = duration.as_secs_f64();
+    for (bound, bucket) in DURATION_BUCKETS.iter().zip(&self.buckets) {
+      if secs <= *bound {
+        bucket.fetch_add(1, Ordering::SeqCst);
+      }
+    }
+    self.count.fetch_add(1, Ordering::SeqCst);
+    self
+      .sum_micros
+      .fetch_add(duration.as_micros() as u64, Ordering::SeqCst);
+  }
+
+  pub fn render(&self, name: &str, out: &mut String) {
+    let count = self.count.load(Ordering::SeqCst);
+    out.push_str(&format!("# TYPE {} histogram\n", name));
+    for (bound, bucket) in DURATION_BUCKETS.iter().zip(&self.buckets) {
+      out.push_str(&format!(
+        "{}_bucket{{le=\"{}\"}} {}\n",
+        name,
+        bound,
+        bucket.load(Ordering::SeqCst)
+      ));
+    }
+    out.push_str(&format!("{}_bucket{{le=\"+Inf\"}} {}\n", name, count));
+    out.push_str(&format!(
+      "{}_sum {}\n",
+      name,
+      self.sum_micros.load(Ordering::SeqCst) as f64 / 1_000_000.0
+    ));
+    out.push_str(&format!("{}_count {}\n", name, count));
+  }
+}
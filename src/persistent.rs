@@ -0,0 +1,252 @@
+//! Warm binary processes that keep a model loaded between generations,
+//! for binaries whose `--help` lists `KEEP_ALIVE_FLAG`.
+//!
+//! A warm process is started with the arguments that load the model and
+//! `KEEP_ALIVE_FLAG`. It then reads one job per line on stdin, a JSON array
+//! of the arguments that would have followed the model on a command line,
+//! and writes `JOB_EXIT` and the job's exit code on a line of stderr once the
+//! job's output is written. Anything else it prints belongs to the job
+//! running at the time.
+
+use crate::process::ReapedChild;
+use std::io;
+use std::process::{ExitStatus, Output, Stdio};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{ChildStderr, ChildStdin, Command};
+
+/// Flag starting a binary in keep-alive mode.
+pub const KEEP_ALIVE_FLAG: &str = "--keep-alive";
+
+/// Prefix of the stderr line ending each job.
+const JOB_EXIT: &str = "sd-job-exit ";
+
+/// A generation for a warm process.
+pub struct WarmJob {
+  /// The binary and the arguments loading the model, which identify the
+  /// process.
+  pub command: Vec<String>,
+  pub args: Vec<String>,
+}
+
+/// Idle warm processes, the most recently used last.
+pub struct WarmProcesses {
+  pub max_idle: usize,
+  idle: Mutex<Vec<WarmProcess>>,
+}
+
+impl WarmProcesses {
+  pub fn new(max_idle: usize) -> Self {
+    WarmProcesses {
+      max_idle,
+      idle: Mutex::new(Vec::new()),
+    }
+  }
+
+  /// Runs `job` on an idle process for its model, starting one when none is
+  /// idle, such as on the first job or while another job holds it. A process
+  /// found to have exited since its last job is restarted.
+  ///
+  /// The process is only kept for later jobs once this job has finished, so
+  /// one dropped on a timeout is killed rather than reused mid-job.
+  pub async fn run(&self, job: &WarmJob) -> io::Result<Output> {
+    let idle = {
+      let mut idle = self.idle.lock().unwrap();
+      idle
+        .iter()
+        .position(|process| process.command == job.command)
+        .map(|index| idle.remove(index))
+    };
+    let mut process = match idle {
+      Some(mut process) => match process.child.try_wait() {
+        Ok(None) => process,
+        status => {
+          tracing::warn!(
+            binary = %job.command[0],
+            status = ?status,
+            "warm process exited, restarting it"
+          );
+          WarmProcess::start(&job.command)?
+        }
+      },
+      None => WarmProcess::start(&job.command)?,
+    };
+    let output = process.run(&job.args).await?;
+
+    let mut idle = self.idle.lock().unwrap();
+    if !idle.iter().any(|idle| idle.command == job.command) {
+      idle.push(process);
+      // Each idle process holds its model in memory.
+      if idle.len() > self.max_idle {
+        idle.remove(0);
+      }
+    }
+    Ok(output)
+  }
+}
+
+struct WarmProcess {
+  command: Vec<String>,
+  child: ReapedChild,
+  stdin: ChildStdin,
+  stderr: BufReader<ChildStderr>,
+  /// Read as it comes so a full pipe never blocks the process.
+  stdout: Arc<Mutex<Vec<u8>>>,
+}
+
+impl WarmProcess {
+  fn start(command: &[String]) -> io::Result<Self> {
+    tracing::info!(binary = %command[0], "starting warm process");
+    let mut child = ReapedChild::spawn(
+      Command::new(&command[0])
+        .args(&command[1..])
+        .arg(KEEP_ALIVE_FLAG)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped()),
+    )?;
+    let stdin = child.stdin.take().unwrap();
+    let stderr = BufReader::new(child.stderr.take().unwrap());
+    let mut pipe = child.stdout.take().unwrap();
+    let stdout = Arc::new(Mutex::new(Vec::new()));
+    let buffer = stdout.clone();
+    tokio::spawn(async move {
+      let mut chunk = [0u8; 4096];
+      while let Ok(read @ 1..) = pipe.read(&mut chunk).await {
+        buffer.lock().unwrap().extend_from_slice(&chunk[..read]);
+      }
+    });
+    Ok(WarmProcess {
+      command: command.to_vec(),
+      child,
+      stdin,
+      stderr,
+      stdout,
+    })
+  }
+
+  async fn run(&mut self, args: &[String]) -> io::Result<Output> {
+    self.stdout.lock().unwrap().clear();
+    let mut line = serde_json::to_vec(args)?;
+    line.push(b'\n');
+    self.stdin.write_all(&line).await?;
+    self.stdin.flush().await?;
+
+    let mut stderr = Vec::new();
+    loop {
+      let mut line = Vec::new();
+      if self.stderr.read_until(b'\n', &mut line).await? == 0 {
+        return Err(io::Error::new(
+          io::ErrorKind::UnexpectedEof,
+          format!(
+            "warm process exited during the job: {}",
+            String::from_utf8_lossy(&stderr)
+          ),
+        ));
+      }
+      let Some(code) = String::from_utf8_lossy(&line)
+        .trim_end()
+        .strip_prefix(JOB_EXIT)
+        .map(str::parse::<i32>)
+      else {
+        stderr.extend_from_slice(&line);
+        continue;
+      };
+      let code = code.map_err(|e| {
+        io::Error::new(
+          io::ErrorKind::InvalidData,
+          format!("invalid job exit code: {}", e),
+        )
+      })?;
+      return Ok(Output {
+        status: exit_status(code),
+        stdout: std::mem::take(&mut *self.stdout.lock().unwrap()),
+        stderr,
+      });
+    }
+  }
+}
+
+#[cfg(unix)]
+fn exit_status(code: i32) -> ExitStatus {
+  use std::os::unix::process::ExitStatusExt;
+  ExitStatus::from_raw((code & 0xff) << 8)
+}
+
+#[cfg(windows)]
+fn exit_status(code: i32) -> ExitStatus {
+  use std::os::windows::process::ExitStatusExt;
+  ExitStatus::from_raw(code as u32)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+  use super::*;
+
+  /// A keep-alive binary in shell, writing each job's arguments to stdout.
+  const SCRIPT: &str = r#"
+    echo "loaded $*" >&2
+    while read -r job; do
+      case "$job" in
+        *crash*) exit 3 ;;
+        *fail*) echo "bad job" >&2; echo "sd-job-exit 1" >&2 ;;
+        *) echo "$job"; echo "sd-job-exit 0" >&2 ;;
+      esac
+    done
+  "#;
+
+  fn job(args: &[&str]) -> WarmJob {
+    WarmJob {
+      command: ["sh", "-c", SCRIPT, "sh", "-m", "model.gguf"]
+        .map(String::from)
+        .to_vec(),
+      args: args.iter().map(|arg| arg.to_string()).collect(),
+    }
+  }
+
+  #[tokio::test]
+  async fn reuses_the_process_across_jobs() {
+    let processes = WarmProcesses::new(1);
+    let output = processes.run(&job(&["-p", "a cat"])).await.unwrap();
+    assert!(output.status.success());
+    // The first job also gets what the process printed while loading.
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("loaded -m model.gguf --keep-alive"));
+
+    let output = processes.run(&job(&["-p", "a dog"])).await.unwrap();
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty(), "the model was loaded again");
+    assert_eq!(processes.idle.lock().unwrap().len(), 1);
+  }
+
+  #[tokio::test]
+  async fn reports_failed_jobs() {
+    let processes = WarmProcesses::new(1);
+    let output = processes.run(&job(&["fail"])).await.unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).ends_with("bad job\n"));
+    assert!(processes.run(&job(&["ok"])).await.unwrap().status.success());
+  }
+
+  #[tokio::test]
+  async fn restarts_after_a_crash() {
+    let processes = WarmProcesses::new(1);
+    let error = processes.run(&job(&["crash"])).await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    assert!(processes.idle.lock().unwrap().is_empty());
+    let output = processes.run(&job(&["ok"])).await.unwrap();
+    assert!(output.status.success());
+  }
+
+  #[tokio::test]
+  async fn keeps_at_most_max_idle_processes() {
+    let processes = WarmProcesses::new(1);
+    let mut other = job(&["ok"]);
+    other.command[5] = "other.gguf".to_string();
+    processes.run(&job(&["ok"])).await.unwrap();
+    processes.run(&other).await.unwrap();
+    let idle = processes.idle.lock().unwrap();
+    assert_eq!(idle.len(), 1);
+    assert_eq!(idle[0].command, other.command);
+  }
+}
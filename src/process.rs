@@ -0,0 +1,107 @@
+//! Child processes reaped on every path, including timeouts and dropped
+//! requests, so killed runs do not linger as zombies.
+
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::process::{Output, Stdio};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::{Child, Command};
+
+/// A running child that, when dropped before it exits, is killed and then
+/// waited for in the background. `kill_on_drop` alone only sends the
+/// signal, leaving the exit status to tokio's best-effort reaping.
+pub struct ReapedChild {
+  /// Only taken when dropped.
+  child: Option<Child>,
+}
+
+impl ReapedChild {
+  pub fn spawn(cmd: &mut Command) -> io::Result<Self> {
+    Ok(ReapedChild {
+      child: Some(cmd.kill_on_drop(true).spawn()?),
+    })
+  }
+
+  /// Waits for the child to exit while reading its piped output, as
+  /// `Child::wait_with_output` does without giving up the child.
+  pub async fn wait_with_output(&mut self) -> io::Result<Output> {
+    let stdout = self.stdout.take();
+    let stderr = self.stderr.take();
+    let (status, stdout, stderr) =
+      tokio::try_join!(self.wait(), read_pipe(stdout), read_pipe(stderr))?;
+    Ok(Output {
+      status,
+      stdout,
+      stderr,
+    })
+  }
+}
+
+async fn read_pipe(
+  pipe: Option<impl AsyncRead + Unpin>,
+) -> io::Result<Vec<u8>> {
+  let mut buffer = Vec::new();
+  if let Some(mut pipe) = pipe {
+    pipe.read_to_end(&mut buffer).await?;
+  }
+  Ok(buffer)
+}
+
+impl Deref for ReapedChild {
+  type Target = Child;
+
+  fn deref(&self) -> &Child {
+    self.child.as_ref().unwrap()
+  }
+}
+
+impl DerefMut for ReapedChild {
+  fn deref_mut(&mut self) -> &mut Child {
+    self.child.as_mut().unwrap()
+  }
+}
+
+impl Drop for ReapedChild {
+  fn drop(&mut self) {
+    let Some(mut child) = self.child.take() else {
+      return;
+    };
+    // Reaps the child at once when it has already exited.
+    if !matches!(child.try_wait(), Ok(None)) {
+      return;
+    }
+    if let Err(e) = child.start_kill() {
+      tracing::warn!(error = %e, "failed to kill child process");
+    }
+    // Outside a runtime, when the server is exiting, the child is left to
+    // `kill_on_drop`.
+    if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+      runtime.spawn(async move {
+        if let Err(e) = child.wait().await {
+          tracing::warn!(error = %e, "failed to reap child process");
+        }
+      });
+    }
+  }
+}
+
+/// Runs `cmd` to completion like `Command::output`, killing and reaping it
+/// after `timeout`, which fails with `ErrorKind::TimedOut`.
+pub async fn run_output(
+  cmd: &mut Command,
+  timeout: Option<Duration>,
+) -> io::Result<Output> {
+  let mut child = ReapedChild::spawn(
+    cmd
+      .stdin(Stdio::null())
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped()),
+  )?;
+  match timeout {
+    Some(timeout) => tokio::time::timeout(timeout, child.wait_with_output())
+      .await
+      .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+    None => child.wait_with_output().await,
+  }
+}
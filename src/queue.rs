@@ -0,0 +1,323 @@
+//! Queue limiting how many generations run at once.
+
+use crate::api::ImageGenerationRequest;
+use crate::config::{Context, QueueMode};
+use crate::error::ApiError;
+use actix_web::http::StatusCode;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+
+/// FIFO of requests waiting to run the binary, drained by a fixed pool of
+/// `workers`. A worker hands each request a `GenerationSlot` and waits for it
+/// to be dropped before serving the next one.
+pub struct GenerationQueue {
+  pub workers: usize,
+  sender: mpsc::UnboundedSender<oneshot::Sender<GenerationSlot>>,
+  receiver: tokio::sync::Mutex<
+    mpsc::UnboundedReceiver<oneshot::Sender<GenerationSlot>>,
+  >,
+  /// Requests currently waiting for a slot.
+  pub waiting: AtomicUsize,
+  /// Slots currently handed out.
+  pub running: AtomicUsize,
+  /// Most recent slot hold times, used to estimate waits.
+  durations: Mutex<VecDeque<Duration>>,
+}
+
+/// Permission to run generations; the worker is released when dropped.
+pub struct GenerationSlot {
+  _done: oneshot::Sender<()>,
+}
+
+/// Counts a request in `waiting` until dropped, which also covers requests
+/// dropped while they wait, such as cancelled ones.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl<'a> Waiting<'a> {
+  /// Counts a request unless `full` holds for the current count, checking
+  /// and counting at once so concurrent requests cannot all take the last
+  /// place.
+  fn reserve(
+    waiting: &'a AtomicUsize,
+    full: impl Fn(usize) -> bool,
+  ) -> Option<Self> {
+    waiting
+      .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+        (!full(count)).then_some(count + 1)
+      })
+      .ok()?;
+    Some(Waiting(waiting))
+  }
+}
+
+impl Drop for Waiting<'_> {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::SeqCst);
+  }
+}
+
+/// Number of recent generations averaged for wait estimates.
+const QUEUE_DURATION_SAMPLES: usize = 20;
+
+impl GenerationQueue {
+  pub fn new(workers: usize) -> Self {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    GenerationQueue {
+      workers,
+      sender,
+      receiver: tokio::sync::Mutex::new(receiver),
+      waiting: AtomicUsize::new(0),
+      running: AtomicUsize::new(0),
+      durations: Mutex::new(VecDeque::new()),
+    }
+  }
+
+  /// Position a new request would take in the queue, 0 meaning it runs
+  /// immediately.
+  pub fn position(&self) -> usize {
+    let waiting = self.waiting.load(Ordering::SeqCst);
+    if waiting == 0 && self.running.load(Ordering::SeqCst) < self.workers {
+      0
+    } else {
+      waiting + 1
+    }
+  }
+
+  pub fn average_duration(&self) -> Option<Duration> {
+    let durations = self.durations.lock().unwrap();
+    if durations.is_empty() {
+      return None;
+    }
+    Some(durations.iter().sum::<Duration>() / durations.len() as u32)
+  }
+
+  /// Estimated time before a request at `position` gets a slot.
+  pub fn estimated_wait(&self, position: usize) -> Duration {
+    let batches = position.div_ceil(self.workers) as u32;
+    self.average_duration().unwrap_or_default() * batches
+  }
+
+  pub fn record(&self, duration: Duration) {
+    let mut durations = self.durations.lock().unwrap();
+    if durations.len() == QUEUE_DURATION_SAMPLES {
+      durations.pop_front();
+    }
+    durations.push_back(duration);
+  }
+}
+
+pub async fn run_queue_worker(queue: Arc<GenerationQueue>) {
+  loop {
+    let Some(request) = queue.receiver.lock().await.recv().await else {
+      return;
+    };
+    let (done, finished) = oneshot::channel();
+    // The request may have stopped waiting in the meantime.
+    if request.send(GenerationSlot { _done: done }).is_err() {
+      continue;
+    }
+    queue.running.fetch_add(1, Ordering::SeqCst);
+    let started = Instant::now();
+    let _ = finished.await;
+    queue.running.fetch_sub(1, Ordering::SeqCst);
+    queue.record(started.elapsed());
+  }
+}
+
+/// Waits until the requested GPU is free. Requests without a `device` use
+/// the binary's default and are only limited by the queue.
+pub async fn acquire_device(
+  context: &Context,
+  body: &ImageGenerationRequest,
+) -> Option<tokio::sync::OwnedSemaphorePermit> {
+  let device = context.devices.get(body.device? as usize)?.clone();
+  device.acquire_owned().await.ok()
+}
+
+/// Waits for a free generation slot according to the configured queue mode.
+pub async fn acquire_generation_slot(
+  context: &Context,
+) -> Result<GenerationSlot, ApiError> {
+  context.breaker.check()?;
+  let queue = &context.queue;
+  let position = queue.position();
+  if context.queue_mode == QueueMode::Reject
+    && position > context.queue_threshold
+  {
+    let wait = queue.estimated_wait(position);
+    tracing::warn!(position, "queue over threshold, rejecting");
+    context.metrics.record_failure("rate_limit");
+    return Err(ApiError::rate_limited(
+      format!("Server is busy (queue position {}), retry later", position),
+      wait.as_secs(),
+    ));
+  }
+
+  // A request taking a free worker at once does not count against
+  // `max_queue_depth`.
+  let full = |waiting: usize| {
+    context.max_queue_depth.is_some_and(|max_depth| {
+      waiting >= max_depth
+        && (waiting > 0
+          || queue.running.load(Ordering::SeqCst) >= queue.workers)
+    })
+  };
+  let Some(waiting) = Waiting::reserve(&queue.waiting, full) else {
+    let wait = queue.estimated_wait(queue.position());
+    tracing::warn!(max_depth = ?context.max_queue_depth, "queue full, rejecting");
+    context.metrics.record_failure("service_unavailable");
+    return Err(
+      ApiError::new(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "The generation queue is full, retry later",
+        "service_unavailable",
+      )
+      .with_retry_after(wait.as_secs()),
+    );
+  };
+
+  let (sender, receiver) = oneshot::channel();
+  let enqueued = Instant::now();
+  let slot = if queue.sender.send(sender).is_err() {
+    None
+  } else if let Some(wait) = context.queue_wait {
+    tokio::time::timeout(wait, receiver)
+      .await
+      .ok()
+      .and_then(|slot| slot.ok())
+  } else {
+    receiver.await.ok()
+  };
+  drop(waiting);
+  context
+    .metrics
+    .queue_wait_seconds
+    .observe(enqueued.elapsed());
+
+  slot.ok_or_else(|| {
+    // Until a generation has finished there is no average to estimate
+    // from, so suggest waiting as long again.
+    let retry_after = match queue.average_duration() {
+      Some(_) => queue.estimated_wait(queue.position()),
+      None => context.queue_wait.unwrap_or_default(),
+    };
+    tracing::warn!(retry_after = ?retry_after, "no generation slot available");
+    context.metrics.record_failure("rate_limit");
+    ApiError::rate_limited(
+      "Server is busy, retry later",
+      retry_after.as_secs_f64().ceil() as u64,
+    )
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use actix_web::ResponseError;
+
+  fn test_context() -> Context {
+    Context::for_tests(
+      r#"
+        port = 8080
+        token = "t"
+        binary_path = "/bin/true"
+        models_dir = "/tmp"
+      "#,
+    )
+  }
+
+  #[tokio::test]
+  async fn cancelled_waiter_leaves_the_queue() {
+    let context = test_context();
+    let queue = context.queue.clone();
+    tokio::spawn(run_queue_worker(queue.clone()));
+
+    let slot = acquire_generation_slot(&context).await.unwrap();
+    let waiter = tokio::time::timeout(
+      Duration::from_millis(50),
+      acquire_generation_slot(&context),
+    );
+    assert!(waiter.await.is_err());
+    assert_eq!(queue.waiting.load(Ordering::SeqCst), 0);
+
+    drop(slot);
+    let slot = tokio::time::timeout(
+      Duration::from_secs(1),
+      acquire_generation_slot(&context),
+    )
+    .await
+    .expect("the cancelled waiter kept the slot")
+    .unwrap();
+    assert_eq!(queue.waiting.load(Ordering::SeqCst), 0);
+    drop(slot);
+  }
+
+  #[tokio::test]
+  async fn position_counts_waiters_once_workers_are_busy() {
+    let context = test_context();
+    let queue = context.queue.clone();
+    tokio::spawn(run_queue_worker(queue.clone()));
+
+    assert_eq!(queue.position(), 0);
+    let _slot = acquire_generation_slot(&context).await.unwrap();
+    assert_eq!(queue.position(), 1);
+    let _waiting = Waiting::reserve(&queue.waiting, |_| false).unwrap();
+    assert_eq!(queue.position(), 2);
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn full_queues_reject_at_once() {
+    let context = Arc::new(Context::for_tests(
+      r#"
+        port = 8080
+        token = "t"
+        binary_path = "/bin/true"
+        models_dir = "/tmp"
+        max_queue_depth = 1
+      "#,
+    ));
+    tokio::spawn(run_queue_worker(context.queue.clone()));
+    let _slot = acquire_generation_slot(&context).await.unwrap();
+
+    // However many arrive together, only one request takes the last place
+    // and the others are turned away without waiting.
+    let requests: Vec<_> = (0..8)
+      .map(|_| {
+        let context = context.clone();
+        tokio::spawn(async move {
+          let started = Instant::now();
+          let wait = acquire_generation_slot(&context);
+          let result =
+            tokio::time::timeout(Duration::from_millis(500), wait).await;
+          (result, started.elapsed())
+        })
+      })
+      .collect();
+    let mut rejected = 0;
+    for request in requests {
+      let (result, elapsed) = request.await.unwrap();
+      let Ok(Err(error)) = result else {
+        continue;
+      };
+      rejected += 1;
+      assert!(elapsed < Duration::from_millis(100));
+      assert_eq!(error.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+      let response = error.error_response();
+      assert!(response.headers().contains_key("Retry-After"));
+    }
+    assert_eq!(rejected, 7);
+    assert_eq!(context.queue.waiting.load(Ordering::SeqCst), 0);
+  }
+
+  #[test]
+  fn estimated_wait_is_per_batch_of_workers() {
+    let queue = GenerationQueue::new(2);
+    queue.record(Duration::from_secs(10));
+    queue.record(Duration::from_secs(20));
+    assert_eq!(queue.average_duration(), Some(Duration::from_secs(15)));
+    assert_eq!(queue.estimated_wait(3), Duration::from_secs(30));
+  }
+}
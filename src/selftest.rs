@@ -0,0 +1,91 @@
+//! Startup self-test holding readiness back until the binary has generated
+//! an image, so no traffic is routed to a node that cannot serve it.
+
+use crate::api::ImageGenerationRequest;
+use crate::config::Context;
+use crate::error::ApiError;
+use crate::files::{unique_name, TempFile, OUTPUT_PREFIX};
+use crate::generation::{
+  resolve_throwaway_request, run_generation, validate_request,
+};
+use crate::queue::acquire_generation_slot;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// Delay before a failed self-test runs again.
+const SELF_TEST_RETRY: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum SelfTestStatus {
+  Running,
+  Passed,
+  /// Retried every `SELF_TEST_RETRY` until it passes.
+  Failed {
+    error: String,
+  },
+}
+
+/// Runs the self-test against `model` until it passes once.
+pub async fn run_self_test(context: Context, model: String) {
+  loop {
+    let started = Instant::now();
+    let result = tokio::time::timeout(
+      context.self_test_timeout,
+      self_test(&context, &model),
+    )
+    .await;
+    let error = match result {
+      Ok(Ok(())) => {
+        tracing::info!(
+          model = %model,
+          duration_ms = started.elapsed().as_millis() as u64,
+          "startup self-test passed, ready for traffic"
+        );
+        *context.self_test.lock().unwrap() = Some(SelfTestStatus::Passed);
+        return;
+      }
+      Ok(Err(e)) => e.message().to_string(),
+      Err(_) => format!(
+        "timed out after {} seconds",
+        context.self_test_timeout.as_secs()
+      ),
+    };
+    tracing::error!(
+      model = %model,
+      error = %error,
+      retry_in = ?SELF_TEST_RETRY,
+      "startup self-test failed, not ready for traffic"
+    );
+    *context.self_test.lock().unwrap() = Some(SelfTestStatus::Failed { error });
+    tokio::time::sleep(SELF_TEST_RETRY).await;
+  }
+}
+
+/// A throwaway 64x64 single-step generation, as `warmup_model` runs.
+async fn self_test(context: &Context, model: &str) -> Result<(), ApiError> {
+  let body: ImageGenerationRequest =
+    serde_json::from_value(serde_json::json!({
+      "prompt": "self-test",
+      "model": model,
+      "size": "64x64",
+      "steps": 1,
+      "seed": 0,
+    }))
+    .map_err(|e| ApiError::server_error(e.to_string()))?;
+  validate_request(context, &body)?;
+  let resolved = resolve_throwaway_request(context, &body).await?;
+
+  let _slot = acquire_generation_slot(context).await?;
+  let output = TempFile::output(
+    context,
+    format!(
+      "{}/{}{}_selftest.tmp.png",
+      context.cache_dir,
+      OUTPUT_PREFIX,
+      unique_name()
+    ),
+  );
+  run_generation(context, &body, &resolved, 0, output.path()).await?;
+  Ok(())
+}
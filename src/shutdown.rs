@@ -0,0 +1,129 @@
+//! Graceful shutdown on SIGTERM or SIGINT.
+//!
+//! The server stops accepting connections and waits up to `shutdown_grace`
+//! for the requests it is handling, until their responses are sent, then
+//! stops. Requests still running by then are dropped, which kills their
+//! child processes and removes their temp files.
+//!
+//! actix's own signal handling is disabled: its workers can stop as soon as
+//! its accept loop does, dropping in-flight requests at once.
+
+use crate::config::Context;
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::dev::{ServerHandle, ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::web::{self, Bytes};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, Instant};
+
+/// How often the number of in-flight requests is checked while draining.
+const DRAIN_POLL: Duration = Duration::from_millis(100);
+
+/// Counts a request in `Context::in_flight` until dropped.
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+  fn new(count: &Arc<AtomicUsize>) -> Self {
+    count.fetch_add(1, Ordering::SeqCst);
+    InFlight(count.clone())
+  }
+}
+
+impl Drop for InFlight {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::SeqCst);
+  }
+}
+
+/// A response body keeping its request in flight until it is sent.
+struct InFlightBody {
+  body: BoxBody,
+  _in_flight: InFlight,
+}
+
+impl MessageBody for InFlightBody {
+  type Error = <BoxBody as MessageBody>::Error;
+
+  fn size(&self) -> BodySize {
+    self.body.size()
+  }
+
+  fn poll_next(
+    self: Pin<&mut Self>,
+    cx: &mut TaskContext<'_>,
+  ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+    Pin::new(&mut self.get_mut().body).poll_next(cx)
+  }
+}
+
+/// Counts every request in `Context::in_flight` from when it comes in to
+/// when its response body is sent or dropped.
+pub async fn count_in_flight(
+  req: ServiceRequest,
+  next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+  let in_flight = req
+    .app_data::<web::Data<Context>>()
+    .map(|context| InFlight::new(&context.in_flight));
+  let response = next.call(req).await?.map_into_boxed_body();
+  Ok(response.map_body(|_, body| match in_flight {
+    Some(in_flight) => BoxBody::new(InFlightBody {
+      body,
+      _in_flight: in_flight,
+    }),
+    None => body,
+  }))
+}
+
+/// Drains and stops `server` on SIGTERM or SIGINT.
+pub async fn stop_on_signal(
+  server: ServerHandle,
+  in_flight: Arc<AtomicUsize>,
+  grace: Duration,
+) {
+  shutdown_signal().await;
+  drain(&server, &in_flight, grace).await;
+}
+
+#[cfg(unix)]
+async fn shutdown_signal() {
+  use tokio::signal::unix::{signal, SignalKind};
+  let mut terminate =
+    signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+  tokio::select! {
+    _ = terminate.recv() => {}
+    _ = tokio::signal::ctrl_c() => {}
+  }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+  let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Stops accepting connections, waits up to `grace` for the `in_flight`
+/// requests, then stops `server`, dropping any requests left.
+pub async fn drain(
+  server: &ServerHandle,
+  in_flight: &AtomicUsize,
+  grace: Duration,
+) {
+  tracing::info!(
+    in_flight = in_flight.load(Ordering::SeqCst),
+    grace_secs = grace.as_secs(),
+    "shutting down, waiting for in-flight requests"
+  );
+  server.pause().await;
+  let deadline = Instant::now() + grace;
+  while in_flight.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+    tokio::time::sleep(DRAIN_POLL).await;
+  }
+  let remaining = in_flight.load(Ordering::SeqCst);
+  if remaining > 0 {
+    tracing::warn!(remaining, "grace period over, dropping in-flight requests");
+  }
+  server.stop(false).await;
+}
@@ -0,0 +1,90 @@
+//! Object storage, such as S3 buckets, holding models to download and URL
+//! outputs to upload.
+
+use futures_util::StreamExt;
+use object_store::path::Path;
+use object_store::{ObjectStore, ObjectStoreExt, PutPayload};
+use tokio::io::AsyncWriteExt;
+
+/// A bucket, or local directory, under a prefix. Every backend of
+/// `object_store` is supported, picked by the URL scheme.
+pub struct Storage {
+  store: Box<dyn ObjectStore>,
+  prefix: Path,
+}
+
+impl Storage {
+  /// Opens a store URL such as `s3://bucket/prefix`. Credentials, region and
+  /// endpoint are read from the usual `AWS_*` environment variables.
+  pub fn open(url: &str) -> Result<Self, String> {
+    let url = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+    let (store, prefix) = object_store::parse_url_opts(&url, std::env::vars())
+      .map_err(|e| e.to_string())?;
+    Ok(Storage { store, prefix })
+  }
+
+  pub fn is_remote(url: &str) -> bool {
+    url.contains("://")
+  }
+
+  fn path(&self, name: &str) -> Path {
+    self.prefix.clone().join(name)
+  }
+
+  /// Names of the objects right under the prefix.
+  pub async fn list(&self) -> object_store::Result<Vec<String>> {
+    let listing = self.store.list_with_delimiter(Some(&self.prefix)).await?;
+    Ok(
+      listing
+        .objects
+        .into_iter()
+        .filter_map(|object| object.location.filename().map(str::to_string))
+        .collect(),
+    )
+  }
+
+  /// Downloads `name` to `dest`, returning false when there is no such
+  /// object. The file only appears at `dest` once complete.
+  pub async fn download(
+    &self,
+    name: &str,
+    dest: &str,
+  ) -> object_store::Result<bool> {
+    let result = match self.store.get(&self.path(name)).await {
+      Ok(result) => result,
+      Err(object_store::Error::NotFound { .. }) => return Ok(false),
+      Err(e) => return Err(e),
+    };
+    let partial = format!("{}.{}.part", dest, uuid::Uuid::new_v4().simple());
+    let written = async {
+      let mut file = tokio::fs::File::create(&partial).await?;
+      let mut chunks = result.into_stream();
+      while let Some(chunk) = chunks.next().await {
+        file.write_all(&chunk?).await?;
+      }
+      file.sync_all().await?;
+      tokio::fs::rename(&partial, dest).await
+    }
+    .await;
+    if let Err(e) = written {
+      let _ = tokio::fs::remove_file(&partial).await;
+      return Err(object_store::Error::Generic {
+        store: "local",
+        source: Box::new(e),
+      });
+    }
+    Ok(true)
+  }
+
+  pub async fn upload(
+    &self,
+    name: &str,
+    data: Vec<u8>,
+  ) -> object_store::Result<()> {
+    self
+      .store
+      .put(&self.path(name), PutPayload::from(data))
+      .await
+      .map(|_| ())
+  }
+}
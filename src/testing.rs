@@ -0,0 +1,65 @@
+//! Fixtures shared by the tests of several modules.
+
+use crate::config::Context;
+use crate::files::unique_name;
+use std::path::{Path, PathBuf};
+
+/// A temporary models directory holding `foo.gguf`.
+pub fn models_dir() -> PathBuf {
+  let dir = std::env::temp_dir().join(format!("sd-models-{}", unique_name()));
+  std::fs::create_dir_all(&dir).unwrap();
+  std::fs::write(dir.join("foo.gguf"), b"GGUF\x03\0\0\0\0").unwrap();
+  dir
+}
+
+/// An executable `sd` in `dir` running `script` with `sh`.
+#[cfg(unix)]
+pub fn fake_binary(dir: &Path, script: &str) -> PathBuf {
+  use std::os::unix::fs::PermissionsExt;
+  let binary = dir.join("sd");
+  std::fs::write(&binary, format!("#!/bin/sh\n{}", script)).unwrap();
+  std::fs::set_permissions(&binary, std::fs::Permissions::from_mode(0o755))
+    .unwrap();
+  binary
+}
+
+/// A `fake_binary` running `before` in `dir`, then copying an 8x8 PNG to
+/// the path of its `-o` argument, `$out`.
+#[cfg(unix)]
+pub fn image_binary(dir: &Path, before: &str) -> PathBuf {
+  image::DynamicImage::new_rgb8(8, 8)
+    .save(dir.join("image.png"))
+    .unwrap();
+  fake_binary(
+    dir,
+    &format!(
+      r#"
+        while [ $# -gt 1 ]; do [ "$1" = -o ] && out=$2; shift; done
+        cd {}
+        {}
+        cp image.png "$out"
+      "#,
+      dir.display(),
+      before
+    ),
+  )
+}
+
+/// A context running `binary` with `dir` as its models and cache
+/// directories, and the `extra` settings.
+pub fn fake_context(binary: &Path, dir: &Path, extra: &str) -> Context {
+  Context::for_tests(&format!(
+    r#"
+      port = 8080
+      token = "t"
+      binary_path = "{}"
+      models_dir = "{}"
+      cache_dir = "{}"
+      {}
+    "#,
+    binary.display(),
+    dir.display(),
+    dir.display(),
+    extra
+  ))
+}